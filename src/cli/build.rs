@@ -1,14 +1,14 @@
 use std::error::Error;
 use std::thread;
 use std::thread::JoinHandle;
-use git;
+use git::Repo;
 use log::{Log, Output};
 use remote::Remote;
 use config::{Config, Host};
 
-pub fn build(config: Config) -> Result<i32, Box<Error>> {
-    let output = Output::new();
-    let hash = try!(git::commit(&output));
+pub fn build(config: Config, output: &Output) -> Result<i32, Box<Error>> {
+    let repo = Repo::new(&config.shadow_dir, output);
+    let hash = try!(repo.commit(&config.gc));
 
     spawn()
 }
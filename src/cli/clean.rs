@@ -4,11 +4,10 @@ use log::Output;
 use remote::Remote;
 use config::Config;
 
-pub fn clean(config: Config) -> Result<i32, Box<Error>> {
-    let output = Output::new();
+pub fn clean(config: Config, output: &Output) -> Result<i32, Box<Error>> {
 
     for host in config.hosts.values() {
-        let remote = Remote::new(host, &output);
+        let remote = Remote::new(host, output);
         try!(remote.init());
     }
 
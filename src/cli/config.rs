@@ -0,0 +1,37 @@
+use std::error::Error;
+use rustc_serialize::json::as_pretty_json;
+use yaml_rust::YamlEmitter;
+use log::{Log, Output};
+use config;
+use config::Config;
+use value;
+
+// Reading the configuration already validated it, so there is nothing left
+// to do but say so.
+pub fn config_check(config: Config, output: &Output) -> Result<i32, Box<Error>> {
+    let log = Log::new("local", output);
+    log.success(&format!("configuration is valid ({} hosts)", config.hosts.len()));
+    Ok(0)
+}
+
+pub fn config_schema() -> Result<i32, Box<Error>> {
+    println!("{}", as_pretty_json(&value::to_json(&config::schema())));
+    Ok(0)
+}
+
+pub fn config_show(config: Config, format: &str) -> Result<i32, Box<Error>> {
+    let resolved = config.to_value();
+
+    match format {
+        "json" => println!("{}", as_pretty_json(&value::to_json(&resolved))),
+        _ => {
+            let mut out = String::new();
+            try!(YamlEmitter::new(&mut out)
+                     .dump(&value::to_yaml(&resolved))
+                     .map_err(|_| "failed to write the configuration as YAML"));
+            println!("{}", out);
+        }
+    }
+
+    Ok(0)
+}
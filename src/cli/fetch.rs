@@ -0,0 +1,36 @@
+use std::error::Error;
+use artifacts;
+use log::{Log, Output};
+use summary;
+use summary::Summary;
+use config::Config;
+
+pub fn fetch(config: Config, output: &Output) -> Result<i32, Box<Error>> {
+    let mut results = Vec::new();
+
+    for host in config.hosts.values() {
+        let log = Log::new(&host.name, output);
+
+        match artifacts::fetch(host, output) {
+            Ok(artifacts) => {
+                let mut result = Summary::new(&host.name, true);
+                result.artifacts = artifacts;
+                results.push(result);
+            }
+            Err(err) => {
+                log.error(&format!("Fetching artifacts failed: {}", err));
+                let mut result = Summary::new(&host.name, false);
+                result.artifacts_error = Some(err.to_string());
+                results.push(result);
+            }
+        }
+    }
+
+    summary::print("Fetch", &results, output);
+
+    if summary::succeeded(&results) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
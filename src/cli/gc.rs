@@ -0,0 +1,57 @@
+use std::error::Error;
+use git::Repo;
+use log::{Log, Output};
+use remote::Remote;
+use config::Config;
+
+fn format_size(kib: u64) -> String {
+    if kib >= 1024 * 1024 {
+        format!("{:.1} GiB", kib as f64 / (1024.0 * 1024.0))
+    } else if kib >= 1024 {
+        format!("{:.1} MiB", kib as f64 / 1024.0)
+    } else {
+        format!("{} KiB", kib)
+    }
+}
+
+fn report(log: &Log, sizes: (u64, u64)) {
+    let (before, after) = sizes;
+    let reclaimed = before.saturating_sub(after);
+
+    log.success(&format!("Reclaimed {} ({} -> {})",
+                         format_size(reclaimed),
+                         format_size(before),
+                         format_size(after)));
+}
+
+pub fn gc(config: Config, output: &Output) -> Result<i32, Box<Error>> {
+    let mut success = true;
+
+    let local_log = Log::new("local", output);
+    match Repo::new(&config.shadow_dir, output).gc() {
+        Ok(sizes) => report(&local_log, sizes),
+        Err(err) => {
+            local_log.error(&format!("Garbage collection failed: {}", err));
+            success = false;
+        }
+    }
+
+    for host in config.hosts.values() {
+        let remote = Remote::new(host, output);
+        let log = Log::new(&host.name, output);
+
+        match remote.gc() {
+            Ok(sizes) => report(&log, sizes),
+            Err(err) => {
+                log.error(&format!("Garbage collection failed: {}", err));
+                success = false;
+            }
+        }
+    }
+
+    if success {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
@@ -1,16 +1,108 @@
+use std::env;
 use std::error::Error;
-use git;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufRead, Write};
+use libc;
+use git::Repo;
 use log::Output;
 use remote::Remote;
 use config::Config;
+use scaffold;
+use scaffold::{HostSpec, ProjectType};
 
-pub fn init(config: Config) -> Result<i32, Box<Error>> {
-    let output = Output::new();
+fn prompt(question: &str) -> Result<String, Box<Error>> {
+    print!("{}: ", question);
+    try!(io::stdout().flush());
 
-    try!(git::init(&output));
+    let mut answer = String::new();
+    try!(io::stdin().lock().read_line(&mut answer));
+    Ok(answer.trim().into())
+}
+
+fn ask_hosts() -> Result<Vec<HostSpec>, Box<Error>> {
+    let mut hosts = Vec::new();
+
+    loop {
+        let answer = try!(prompt("Host to build on, as user@address:build_dir (empty when done)"));
+
+        if answer.is_empty() {
+            return Ok(hosts);
+        }
+
+        match HostSpec::parse(&answer) {
+            Ok(host) => hosts.push(host),
+            Err(err) => println!("{}", err),
+        }
+    }
+}
+
+fn ask_build(proposed: &[String]) -> Result<Vec<String>, Box<Error>> {
+    let question = if proposed.is_empty() {
+        "Build command".to_string()
+    } else {
+        format!("Build command [{}]", proposed.join(" && "))
+    };
+
+    let answer = try!(prompt(&question));
+
+    if answer.is_empty() {
+        Ok(proposed.to_vec())
+    } else {
+        Ok(vec![answer])
+    }
+}
+
+// Writes a bran.yml for a project that doesn't have a configuration yet,
+// from the flags given to "bran init", asking for anything missing when
+// there is someone to ask.
+pub fn scaffold(hosts: &[&str], build: &[&str], interactive: bool) -> Result<(), Box<Error>> {
+    let interactive = interactive && unsafe { libc::isatty(0) != 0 };
+
+    let mut hosts: Vec<HostSpec> = try!(hosts.iter().map(|spec| HostSpec::parse(spec)).collect());
+    let mut build: Vec<String> = build.iter().map(|command| command.to_string()).collect();
+
+    if hosts.is_empty() && interactive {
+        hosts = try!(ask_hosts());
+    }
+
+    if hosts.is_empty() {
+        return Err("No configuration found; pass --host user@address:build_dir to bran init to \
+                    create bran.yml"
+                       .into());
+    }
+
+    if build.is_empty() {
+        let project_type = ProjectType::detect(&try!(env::current_dir()));
+
+        if let Some(project_type) = project_type {
+            println!("Found {}, proposing build commands for it", project_type.file());
+        }
+
+        let proposed = project_type.map_or(Vec::new(), |project_type| {
+            project_type.build_commands()
+        });
+        build = if interactive { try!(ask_build(&proposed)) } else { proposed };
+    }
+
+    if build.is_empty() {
+        return Err("No build command; pass --build to bran init".into());
+    }
+
+    // Never overwrites a bran.yml that appeared in the meantime.
+    let mut file = try!(OpenOptions::new().write(true).create_new(true).open("bran.yml"));
+    try!(file.write_all(scaffold::render(&hosts, &build).as_bytes()));
+
+    println!("Created bran.yml with {} hosts", hosts.len());
+    Ok(())
+}
+
+pub fn init(config: Config, output: &Output) -> Result<i32, Box<Error>> {
+
+    try!(Repo::new(&config.shadow_dir, output).init());
 
     for host in config.hosts.values() {
-        let remote = Remote::new(host, &output);
+        let remote = Remote::new(host, output);
         try!(remote.init());
     }
 
@@ -4,11 +4,23 @@ pub use self::build::build;
 mod clean;
 pub use self::clean::clean;
 
+mod config;
+pub use self::config::{config_check, config_schema, config_show};
+
+mod fetch;
+pub use self::fetch::fetch;
+
+mod gc;
+pub use self::gc::gc;
+
 mod init;
-pub use self::init::init;
+pub use self::init::{init, scaffold};
 
 mod push;
 pub use self::push::push;
 
 mod run;
 pub use self::run::run;
+
+mod watch;
+pub use self::watch::watch;
@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, UNIX_EPOCH};
+use build::build_all;
+use cancel::Cancel;
+use config::Config;
+use git::Repo;
+use log::{Log, Output, Stream};
+
+const POLL_INTERVAL_MS: u64 = 250;
+
+// How long the tree must stay unchanged before a burst of saves counts as
+// finished.
+const DEBOUNCE_MS: u64 = 500;
+
+fn fingerprint(paths: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    for path in paths {
+        path.hash(&mut hasher);
+
+        if let Ok(metadata) = fs::metadata(path) {
+            metadata.len().hash(&mut hasher);
+
+            if let Ok(modified) = metadata.modified() {
+                if let Ok(since_epoch) = modified.duration_since(UNIX_EPOCH) {
+                    since_epoch.hash(&mut hasher);
+                }
+            }
+        }
+    }
+
+    hasher.finish()
+}
+
+// The synced files and the directories holding them. Listing the files runs
+// git, so each poll only looks at their metadata, and they are listed again
+// when a directory changes since that is where added and removed files show.
+struct Tree {
+    files: Vec<String>,
+    dirs: Vec<String>,
+    dirs_fingerprint: u64,
+}
+
+impl Tree {
+    fn list(repo: &Repo) -> Result<Tree, Box<Error>> {
+        let files = try!(repo.files());
+        let mut dirs = BTreeSet::new();
+        dirs.insert(".".to_string());
+
+        for file in &files {
+            let mut path = Path::new(file);
+
+            while let Some(parent) = path.parent() {
+                if parent.as_os_str().is_empty() {
+                    break;
+                }
+
+                dirs.insert(parent.to_string_lossy().into_owned());
+                path = parent;
+            }
+        }
+
+        let dirs: Vec<String> = dirs.into_iter().collect();
+        let dirs_fingerprint = fingerprint(&dirs);
+
+        Ok(Tree {
+            files: files,
+            dirs: dirs,
+            dirs_fingerprint: dirs_fingerprint,
+        })
+    }
+
+    fn fingerprint(&mut self, repo: &Repo) -> Result<u64, Box<Error>> {
+        if fingerprint(&self.dirs) != self.dirs_fingerprint {
+            *self = try!(Tree::list(repo));
+        }
+
+        Ok(fingerprint(&self.files))
+    }
+}
+
+fn wait_for_quiet(repo: &Repo, tree: &mut Tree, mut current: u64) -> Result<u64, Box<Error>> {
+    loop {
+        thread::sleep(Duration::from_millis(DEBOUNCE_MS));
+
+        let next = try!(tree.fingerprint(repo));
+        if next == current {
+            return Ok(current);
+        }
+
+        current = next;
+    }
+}
+
+// A rebuild running in the background, and whether it is over yet.
+struct Run {
+    handle: JoinHandle<()>,
+    done: Arc<AtomicBool>,
+}
+
+// Watch keeps going after a failed rebuild, so the reason is logged here
+// rather than returned.
+fn spawn_run(config: &Arc<Config>, output: &Output, cancel: &Cancel) -> Run {
+    let thread_config = config.clone();
+    let thread_output = output.clone();
+    let thread_cancel = cancel.clone();
+    let done = Arc::new(AtomicBool::new(false));
+    let thread_done = done.clone();
+
+    let handle = thread::spawn(move || {
+        if let Err(err) = build_all(&thread_config, &thread_output, &thread_cancel) {
+            Log::new("watch", &thread_output).error(&format!("Rebuild failed: {}", err));
+        }
+
+        thread_done.store(true, Ordering::SeqCst);
+    });
+
+    Run {
+        handle: handle,
+        done: done,
+    }
+}
+
+// A task replaces the build commands of every host.
+pub fn watch(mut config: Config,
+             task: Option<String>,
+             output: &Output)
+             -> Result<i32, Box<Error>> {
+    if let Some(task) = task {
+        for host in config.hosts.values_mut() {
+            host.build = vec![task.clone()];
+        }
+    }
+
+    let log = Log::new("watch", output);
+    let repo = Repo::new(&config.shadow_dir, output);
+    let config = Arc::new(config);
+
+    let mut tree = try!(Tree::list(&repo));
+    let mut current = fingerprint(&tree.files);
+    let mut cancel = Cancel::new();
+    let mut running = Some(spawn_run(&config, output, &cancel));
+
+    loop {
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+        let next = try!(tree.fingerprint(&repo));
+        if next == current {
+            continue;
+        }
+
+        current = try!(wait_for_quiet(&repo, &mut tree, next));
+
+        if let Some(run) = running.take() {
+            if !run.done.load(Ordering::SeqCst) {
+                log.line(Stream::StdOut, "Changes detected, cancelling the current run");
+                cancel.cancel();
+            }
+
+            run.handle.join().ok();
+        }
+
+        log.line(Stream::StdOut, "Rebuilding");
+        cancel = Cancel::new();
+        running = Some(spawn_run(&config, output, &cancel));
+    }
+}
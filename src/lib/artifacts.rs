@@ -0,0 +1,205 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+use std::process::Command;
+use cmd;
+use config::Host;
+use log::{Log, Output};
+use remote::Remote;
+
+pub const ARTIFACTS_DIR: &'static str = "bran-artifacts";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Artifact {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+// Lists every regular file matching the patterns as "<sha256> <size> <path>".
+// The patterns are left unquoted so that the remote shell expands them.
+fn listing_command(patterns: &[String]) -> String {
+    format!("for f in {}; do if [ -f \"$f\" ]; then echo \"$(sha256sum < \"$f\" | cut -d' ' \
+             -f1) $(wc -c < \"$f\") $f\"; fi; done",
+            patterns.join(" "))
+}
+
+fn parse_listing_line(line: &str) -> Option<Artifact> {
+    let line = line.trim();
+    let hash_end = match line.find(char::is_whitespace) {
+        Some(index) => index,
+        None => return None,
+    };
+
+    let rest = line[hash_end..].trim_left();
+    let size_end = match rest.find(char::is_whitespace) {
+        Some(index) => index,
+        None => return None,
+    };
+
+    let size = match rest[..size_end].parse() {
+        Ok(size) => size,
+        Err(_) => return None,
+    };
+
+    let path = rest[size_end..].trim_left();
+    if path.is_empty() {
+        return None;
+    }
+
+    Some(Artifact {
+        path: path.into(),
+        size: size,
+        sha256: line[..hash_end].into(),
+    })
+}
+
+pub fn parse_listing(listing: &str) -> Vec<Artifact> {
+    listing.lines().filter_map(parse_listing_line).collect()
+}
+
+// sha256sum is missing on macOS, which has "shasum -a 256" instead.
+fn local_sha256(path: &Path, log: &Log) -> Result<String, io::Error> {
+    log.plumbing(&format!("sha256sum {}", path.display()));
+
+    let mut command = Command::new("sha256sum");
+    command.arg(path);
+
+    let sums = match cmd::output(command, log) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut command = Command::new("shasum");
+            command.args(&["-a", "256"]).arg(path);
+            cmd::output(command, log)
+        }
+        result => result,
+    };
+
+    Ok(try!(sums).split_whitespace().next().unwrap_or("").into())
+}
+
+// Paths come from the remote host, and host names can come from the
+// inventory command, so neither is trusted to stay inside ARTIFACTS_DIR.
+fn is_relative_and_inside(path: &str) -> bool {
+    let mut components = Path::new(path).components().peekable();
+
+    components.peek().is_some() &&
+    components.all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+fn copy(host: &Host, artifact: &Artifact, log: &Log) -> Result<(), io::Error> {
+    for &(what, path) in &[("host name", &host.name), ("artifact path", &artifact.path)] {
+        if !is_relative_and_inside(path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("refusing to copy to {} \"{}\" outside of {}",
+                                              what,
+                                              path,
+                                              ARTIFACTS_DIR)));
+        }
+    }
+
+    let local_path = Path::new(ARTIFACTS_DIR).join(&host.name).join(&artifact.path);
+
+    if let Some(parent) = local_path.parent() {
+        try!(fs::create_dir_all(parent));
+    }
+
+    let local = local_path.to_string_lossy().into_owned();
+    let args = host.scp_command(&artifact.path, &local);
+    log.plumbing(&format!("scp {}", args.join(" ")));
+
+    let mut command = Command::new("scp");
+    command.args(&args);
+    try!(cmd::run(command, log));
+
+    let size = try!(fs::metadata(&local_path)).len();
+    if size != artifact.size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("\"{}\" is {} bytes locally but {} bytes remotely",
+                                          artifact.path,
+                                          size,
+                                          artifact.size)));
+    }
+
+    let sha256 = try!(local_sha256(&local_path, log));
+    if sha256 != artifact.sha256 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("\"{}\" has sha256 {} locally but {} remotely",
+                                          artifact.path,
+                                          sha256,
+                                          artifact.sha256)));
+    }
+
+    Ok(())
+}
+
+pub fn fetch(host: &Host, output: &Output) -> Result<Vec<Artifact>, io::Error> {
+    if host.artifacts.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let log = Log::new(&host.name, output);
+    let remote = Remote::new(host, output);
+    let artifacts = parse_listing(&try!(remote.output(&listing_command(&host.artifacts))));
+
+    for artifact in &artifacts {
+        try!(copy(host, artifact, &log));
+    }
+
+    Ok(artifacts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use libc;
+    use log::{Log, Output};
+    use super::{is_relative_and_inside, local_sha256, parse_listing, Artifact};
+
+    #[test]
+    fn hashes_local_files() {
+        let path = env::temp_dir().join(format!("bran-sha256-{}", unsafe { libc::getpid() }));
+        File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        let log = Log::new("local", &Output::new());
+        assert_eq!(local_sha256(&path, &log).unwrap(),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn keeps_copies_inside_the_artifacts_dir() {
+        assert!(is_relative_and_inside("target/release/bran"));
+        assert!(is_relative_and_inside("./out/my file.txt"));
+        assert!(is_relative_and_inside("linux"));
+        assert!(!is_relative_and_inside("/etc/passwd"));
+        assert!(!is_relative_and_inside("out/../../.bashrc"));
+        assert!(!is_relative_and_inside(".."));
+        assert!(!is_relative_and_inside(""));
+    }
+
+    #[test]
+    fn parses_listing() {
+        let listing = "abc123 42 target/release/bran\n\
+                       def456      7 out/my file.txt\n\
+                       garbage\n";
+
+        assert_eq!(parse_listing(listing),
+                   [Artifact {
+                        path: "target/release/bran".into(),
+                        size: 42,
+                        sha256: "abc123".into(),
+                    },
+                    Artifact {
+                        path: "out/my file.txt".into(),
+                        size: 7,
+                        sha256: "def456".into(),
+                    }]);
+    }
+}
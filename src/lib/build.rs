@@ -0,0 +1,156 @@
+use std::error::Error;
+use std::io;
+use std::thread;
+use std::thread::JoinHandle;
+use artifacts;
+use artifacts::Artifact;
+use cancel::Cancel;
+use git::Repo;
+use log::{Log, Output, Stream};
+use remote::Remote;
+use summary;
+use summary::Summary;
+use config::{Config, Gc, Host};
+
+fn remote_build(host: Host,
+                repo: Repo,
+                hash: String,
+                cmds: Vec<String>,
+                gc: Gc,
+                cancel: Cancel,
+                output: Output)
+                -> Result<io::Result<Vec<Artifact>>, Box<Error>> {
+
+    let log = Log::new(&host.name, &output);
+
+    log.step("push");
+    try!(repo.push(&host));
+
+    log.step("reset");
+    let remote = Remote::with_cancel(&host, &output, &cancel);
+    try!(remote.reset(&hash));
+
+    for (index, cmd) in cmds.iter().enumerate() {
+        log.step(&format!("command {}/{}", index + 1, cmds.len()));
+        try!(remote.run(cmd));
+    }
+
+    // The build is done whether or not its artifacts can be fetched, so
+    // that is reported separately.
+    log.step("artifacts");
+    let artifacts = artifacts::fetch(&host, &output);
+
+    // Collecting waits until the build is done instead of holding it up.
+    log.step("gc");
+    remote.gc_auto(&gc).ok();
+
+    Ok(artifacts)
+}
+
+fn run_remote_build(host: Host,
+                    repo: Repo,
+                    hash: String,
+                    cmds: Vec<String>,
+                    gc: Gc,
+                    cancel: Cancel,
+                    output: Output)
+                    -> Summary {
+    let remote_log = Log::new(&host.name, &output);
+    let name = host.name.clone();
+    let cancelled = cancel.clone();
+
+    remote_log.start();
+
+    let summary = match remote_build(host, repo, hash, cmds, gc, cancel, output) {
+        Ok(fetched) => {
+            remote_log.success("Build succeeded");
+
+            let mut summary = Summary::new(&name, true);
+
+            match fetched {
+                Ok(artifacts) => summary.artifacts = artifacts,
+                Err(err) => {
+                    remote_log.error(&format!("Fetching artifacts failed: {}", err));
+                    summary.artifacts_error = Some(err.to_string());
+                }
+            }
+
+            summary
+        }
+        Err(_) if cancelled.is_cancelled() => {
+            remote_log.line(Stream::StdOut, "Build cancelled");
+
+            let mut summary = Summary::new(&name, false);
+            summary.cancelled = true;
+            summary
+        }
+        Err(_) => {
+            remote_log.error("Build failed");
+            Summary::new(&name, false)
+        }
+    };
+
+    remote_log.finish();
+    summary
+}
+
+fn spawn_build(host: &Host,
+               repo: &Repo,
+               hash: &str,
+               config: &Config,
+               cancel: &Cancel,
+               output: &Output)
+               -> (String, JoinHandle<Summary>) {
+
+    let thread_host = host.clone();
+    let thread_repo = repo.clone();
+    let thread_hash = hash.to_string();
+    let thread_cmds = host.build.clone();
+    let thread_gc = config.gc;
+    let thread_cancel = cancel.clone();
+    let thread_output = output.clone();
+
+    let handle = thread::spawn(move || {
+        run_remote_build(thread_host,
+                         thread_repo,
+                         thread_hash,
+                         thread_cmds,
+                         thread_gc,
+                         thread_cancel,
+                         thread_output)
+    });
+
+    (host.name.to_string(), handle)
+}
+
+fn join_all(threads: Vec<(String, JoinHandle<Summary>)>) -> Vec<Summary> {
+    threads.into_iter()
+           .map(|(name, handle)| handle.join().unwrap_or_else(|_| Summary::new(&name, false)))
+           .collect()
+}
+
+// Commits the work tree and builds it on every host at once, for push,
+// build and watch alike.
+pub fn build_all(config: &Config, output: &Output, cancel: &Cancel) -> Result<i32, Box<Error>> {
+    let repo = Repo::new(&config.shadow_dir, output);
+    let hash = try!(repo.commit(&config.gc));
+
+    let results = join_all(config.hosts
+                                 .values()
+                                 .map(|host| {
+                                     spawn_build(host, &repo, &hash, config, cancel, output)
+                                 })
+                                 .collect());
+
+    repo.gc_auto(&config.gc).ok();
+
+    if summary::should_print(&results) {
+        summary::print("Build", &results, output);
+    }
+
+    if summary::succeeded(&results) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use libc;
+
+struct State {
+    cancelled: bool,
+    children: Vec<u32>,
+    killed: Vec<u32>,
+}
+
+// A handle shared by everything taking part in a run, used to stop the
+// local child processes (and, through them, the remote commands) early.
+#[derive(Clone)]
+pub struct Cancel {
+    state: Arc<Mutex<State>>,
+}
+
+fn kill(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+impl Cancel {
+    pub fn new() -> Self {
+        Cancel {
+            state: Arc::new(Mutex::new(State {
+                cancelled: false,
+                children: Vec::new(),
+                killed: Vec::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().expect("failed to acquire cancel mutex")
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.lock().cancelled
+    }
+
+    pub fn cancel(&self) {
+        let mut state = self.lock();
+        state.cancelled = true;
+
+        let children: Vec<u32> = state.children.drain(..).collect();
+        for pid in children {
+            kill(pid);
+            state.killed.push(pid);
+        }
+    }
+
+    // Returns false (and kills the child) if the run was already cancelled.
+    pub fn register(&self, pid: u32) -> bool {
+        let mut state = self.lock();
+
+        if state.cancelled {
+            kill(pid);
+            false
+        } else {
+            state.children.push(pid);
+            true
+        }
+    }
+
+    // Returns whether the child was killed by cancel, rather than having
+    // finished on its own.
+    pub fn unregister(&self, pid: u32) -> bool {
+        let mut state = self.lock();
+        state.children.retain(|&child| child != pid);
+        state.killed.contains(&pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use super::Cancel;
+
+    #[test]
+    fn only_reports_children_it_killed() {
+        let cancel = Cancel::new();
+
+        let mut finished = Command::new("true").spawn().unwrap();
+        assert!(cancel.register(finished.id()));
+        finished.wait().unwrap();
+        assert!(!cancel.unregister(finished.id()));
+
+        let mut running = Command::new("sleep").arg("5").spawn().unwrap();
+        assert!(cancel.register(running.id()));
+        cancel.cancel();
+        assert!(!running.wait().unwrap().success());
+        assert!(cancel.unregister(running.id()));
+    }
+}
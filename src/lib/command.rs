@@ -1,14 +1,44 @@
 use std::io;
 use std::process::{Command, Stdio};
 use std::thread;
+use std::time::Instant;
+use cancel::Cancel;
 use log::{Log, Stream};
 
-pub fn run(cmd: &str, args: &[&str], log: &Log) -> Result<(), io::Error> {
+fn check_status(status: io::Result<::std::process::ExitStatus>) -> Result<(), io::Error> {
+    match status {
+        Ok(status) => {
+            if status.success() {
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::Other, format!("Command failed with {}", status)))
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Only a command that was killed part way through reports Interrupted, since
+// only that one can have left processes behind on the remote host.
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Command cancelled")
+}
+
+fn run_child(mut command: Command, log: &Log, cancel: Option<&Cancel>) -> Result<(), io::Error> {
+    if cancel.map_or(false, |cancel| cancel.is_cancelled()) {
+        return Err(io::Error::new(io::ErrorKind::Other, "Run cancelled before the command"));
+    }
+
+    let started = Instant::now();
     let mut child = try!(command.stdin(Stdio::null())
                                 .stdout(Stdio::piped())
                                 .stderr(Stdio::piped())
                                 .spawn());
 
+    if let Some(cancel) = cancel {
+        cancel.register(child.id());
+    }
+
     let stderr = child.stderr.take().expect("missing child stderr");
     let stdout = child.stdout.take().expect("missing child stdout");
 
@@ -20,14 +50,39 @@ pub fn run(cmd: &str, args: &[&str], log: &Log) -> Result<(), io::Error> {
     log.stream(stdout, Stream::StdOut);
     stderr_thread.join().expect("failed to join stderr_thread");
 
-    match child.wait() {
-        Ok(status) => {
-            if status.success() {
-                Ok(())
-            } else {
-                Err(io::Error::new(io::ErrorKind::Other, format!("Command failed with {}", status)))
-            }
+    let status = child.wait();
+    let code = status.as_ref().ok().and_then(|status| status.code());
+    log.command_finished(code, &started.elapsed());
+
+    if let Some(cancel) = cancel {
+        if cancel.unregister(child.id()) {
+            return Err(cancelled());
         }
-        Err(err) => Err(err),
     }
+
+    check_status(status)
+}
+
+pub fn run(command: Command, log: &Log) -> Result<(), io::Error> {
+    run_child(command, log, None)
+}
+
+pub fn run_cancellable(command: Command, log: &Log, cancel: &Cancel) -> Result<(), io::Error> {
+    run_child(command, log, Some(cancel))
+}
+
+pub fn output(mut command: Command, log: &Log) -> Result<String, io::Error> {
+    let started = Instant::now();
+    let result = try!(command.stdin(Stdio::null())
+                             .stderr(Stdio::piped())
+                             .output());
+    log.command_finished(result.status.code(), &started.elapsed());
+
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    for line in stderr.lines() {
+        log.line(Stream::StdErr, line);
+    }
+
+    try!(check_status(Ok(result.status)));
+    Ok(String::from_utf8_lossy(&result.stdout).into_owned())
 }
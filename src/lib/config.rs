@@ -1,9 +1,17 @@
 use std::error::Error;
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::io::Read;
 use std::fs::File;
-use yaml_rust::{Yaml, YamlLoader};
-use yaml_rust::yaml::Hash;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use git;
+use diagnostics::{Matcher, Severity, PRESETS, SEVERITIES};
+use ssh_config::SshConfig;
+use value;
+use value::{Format, ParseError, Table, Value};
 
 #[derive(Clone, Debug)]
 pub struct Host {
@@ -11,7 +19,15 @@ pub struct Host {
     pub user: String,
     pub build_dir: String,
     pub host: Option<String>,
+    pub port: Option<u16>,
     pub identity_file: Option<String>,
+    pub artifacts: Vec<String>,
+    pub build: Vec<String>,
+}
+
+// Single quotes keep a remote shell from splitting or expanding the text.
+pub fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
 }
 
 impl Host {
@@ -30,12 +46,41 @@ impl Host {
             args.push(identity_file.to_string());
         }
 
+        if let Some(port) = self.port {
+            args.push("-p".into());
+            args.push(port.to_string());
+        }
+
         args.push(format!("{}@{}", self.user, self.host()));
         args.push("-C".into());
         args.push(cmd.into());
         args
     }
 
+    pub fn scp_command(&self, remote_path: &str, local_path: &str) -> Vec<String> {
+        let mut args = Vec::new();
+
+        args.push("-q".into());
+        args.push("-o".into());
+        args.push("PreferredAuthentications=publickey".into());
+
+        if let Some(ref identity_file) = self.identity_file {
+            args.push("-i".into());
+            args.push(identity_file.to_string());
+        }
+
+        if let Some(port) = self.port {
+            args.push("-P".into());
+            args.push(port.to_string());
+        }
+
+        // scp hands the remote path to the remote shell.
+        let path = format!("{}/{}", self.build_dir, remote_path);
+        args.push(format!("{}@{}:{}", self.user, self.host(), shell_quote(&path)));
+        args.push(local_path.into());
+        args
+    }
+
     pub fn git_ssh_command(&self) -> Option<String> {
         if let Some(ref identity_file) = self.identity_file {
             Some(format!("ssh -i \"{}\"", identity_file))
@@ -44,133 +89,1424 @@ impl Host {
         }
     }
 
+    // Only the ssh:// form of URL can have a port, and its paths are
+    // absolute, so relative ones go through "~".
     pub fn git_ssh_url(&self) -> String {
-        format!("{}@{}:{}", self.user, self.host(), self.build_dir)
+        match self.port {
+            Some(port) if self.build_dir.starts_with('/') => {
+                format!("ssh://{}@{}:{}{}", self.user, self.host(), port, self.build_dir)
+            }
+            Some(port) => {
+                format!("ssh://{}@{}:{}/~/{}", self.user, self.host(), port, self.build_dir)
+            }
+            None => format!("{}@{}:{}", self.user, self.host(), self.build_dir),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        let mut table = Table::new();
+
+        insert(&mut table, "user", Value::String(self.user.clone()));
+        insert(&mut table, "build_dir", Value::String(self.build_dir.clone()));
+
+        if let Some(ref host) = self.host {
+            insert(&mut table, "host", Value::String(host.clone()));
+        }
+
+        if let Some(port) = self.port {
+            insert(&mut table, "port", Value::Integer(port as i64));
+        }
+
+        if let Some(ref identity_file) = self.identity_file {
+            insert(&mut table, "identity_file", Value::String(identity_file.clone()));
+        }
+
+        insert(&mut table, "artifacts", string_list(&self.artifacts));
+        insert(&mut table, "build", string_list(&self.build));
+        Value::Table(table)
     }
 }
 
+fn insert(table: &mut Table, key: &str, value: Value) {
+    table.insert(key.into(), value);
+}
+
+fn string_list(values: &[String]) -> Value {
+    Value::Array(values.iter().map(|value| Value::String(value.clone())).collect())
+}
+
+fn pattern_to_value(matcher: &Matcher) -> Value {
+    let mut pattern = Table::new();
+    insert(&mut pattern, "regex", Value::String(matcher.pattern().into()));
+
+    if let Some(severity) = matcher.severity() {
+        insert(&mut pattern, "severity", Value::String(severity.name().into()));
+    }
+
+    Value::Table(pattern)
+}
+
 pub type Hosts = HashMap<String, Host>;
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Gc {
+    // Not a number of commits kept: once the history is longer than this,
+    // all of it is squashed into a single commit.
+    pub max_history: u64,
+    pub auto: u64,
+}
+
+impl Default for Gc {
+    fn default() -> Self {
+        Gc {
+            max_history: 50,
+            auto: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DiagnosticsConfig {
+    pub matchers: Vec<Matcher>,
+    pub max_errors: usize,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            matchers: PRESETS.iter().filter_map(|name| Matcher::preset(name)).collect(),
+            max_errors: 5,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub hosts: Hosts,
     pub build: Vec<String>,
+    pub gc: Gc,
+    pub shadow_dir: PathBuf,
+    pub diagnostics: DiagnosticsConfig,
 }
 
 impl Config {
-    pub fn new(hosts: Hosts, build: Vec<String>) -> Self {
+    pub fn new(hosts: Hosts,
+               build: Vec<String>,
+               gc: Gc,
+               shadow_dir: PathBuf,
+               diagnostics: DiagnosticsConfig)
+               -> Self {
         Config {
             hosts: hosts,
             build: build,
+            gc: gc,
+            shadow_dir: shadow_dir,
+            diagnostics: diagnostics,
+        }
+    }
+
+    // The configuration as bran understands it, with every default filled
+    // in, for "bran config show".
+    pub fn to_value(&self) -> Value {
+        let mut hosts = Table::new();
+        for host in self.hosts.values() {
+            insert(&mut hosts, &host.name, host.to_value());
+        }
+
+        let mut gc = Table::new();
+        insert(&mut gc, "max_history", Value::Integer(self.gc.max_history as i64));
+        insert(&mut gc, "auto", Value::Integer(self.gc.auto as i64));
+
+        let patterns = self.diagnostics.matchers.iter().map(pattern_to_value).collect();
+
+        let mut diagnostics = Table::new();
+        insert(&mut diagnostics,
+               "max_errors",
+               Value::Integer(self.diagnostics.max_errors as i64));
+        insert(&mut diagnostics, "patterns", Value::Array(patterns));
+
+        let mut settings = Table::new();
+        insert(&mut settings, "hosts", Value::Table(hosts));
+        insert(&mut settings, "build", string_list(&self.build));
+        insert(&mut settings, "gc", Value::Table(gc));
+        insert(&mut settings,
+               "shadow_dir",
+               Value::String(self.shadow_dir.to_string_lossy().into_owned()));
+        insert(&mut settings, "diagnostics", Value::Table(diagnostics));
+        Value::Table(settings)
+    }
+}
+
+// An error in the configuration. Errors about a value are made with the
+// path of its key, such as ["hosts", "foo", "user"], and placed in the file
+// once parsing is done.
+#[derive(Debug)]
+pub struct ConfigError {
+    msg: String,
+    path: Vec<String>,
+    position: Option<(usize, usize)>,
+    file: Option<String>,
+    source_line: Option<String>,
+    width: usize,
+}
+
+impl ConfigError {
+    fn new(msg: &str, path: &[&str]) -> Self {
+        ConfigError {
+            msg: msg.into(),
+            path: path.iter().map(|key| key.to_string()).collect(),
+            position: None,
+            file: None,
+            source_line: None,
+            width: path.last().map_or(1, |key| ::std::cmp::max(1, key.chars().count())),
+        }
+    }
+
+    fn from_parse_error(err: ParseError) -> Self {
+        let path: Vec<&str> = err.path.iter().map(|key| key.as_str()).collect();
+        let mut error = ConfigError::new(&err.msg, &path);
+        error.position = err.position;
+        error
+    }
+
+    fn path(&self) -> Vec<&str> {
+        self.path.iter().map(|key| key.as_str()).collect()
+    }
+
+    // Places the error in a file it is known to be about.
+    fn place_in(mut self, file: &str, format: Format, source: &str) -> Self {
+        if self.position.is_none() && !self.path.is_empty() {
+            self.position = locate(source, format, &self.path());
+        }
+
+        if let Some((line, _)) = self.position {
+            self.file = Some(file.into());
+            self.source_line = source.lines().nth(line - 1).map(|text| text.into());
+        }
+
+        self
+    }
+
+    // Places the error in the last of the merged files that has its key,
+    // which is the one whose value was used.
+    fn place<'a, I>(self, sources: I) -> Self
+        where I: DoubleEndedIterator<Item = &'a Source>
+    {
+        if self.path.is_empty() {
+            return self;
+        }
+
+        let found = sources.rev().find(|source| source.locate(&self.path()).is_some());
+
+        match found {
+            Some(source) => self.place_in(&source.name, source.format, &source.contents),
+            None => self,
+        }
+    }
+
+    pub fn position(&self) -> Option<(usize, usize)> {
+        self.position
+    }
+
+    // Renders the error the way compilers do, with the offending line and a
+    // caret under the key or character it is about.
+    pub fn render(&self) -> String {
+        let (line, column) = match self.position {
+            Some(position) => position,
+            None => return self.msg.clone(),
+        };
+
+        let file = self.file.as_ref().map_or("bran.yml", |file| file.as_str());
+
+        let mut rendered = format!("{}\n --> {}:{}:{}", self.msg, file, line, column);
+
+        if let Some(ref text) = self.source_line {
+            let gutter: String = iter::repeat(' ').take(line.to_string().len()).collect();
+            let indent: String = text.chars()
+                                     .take(column - 1)
+                                     .map(|c| if c == '\t' { '\t' } else { ' ' })
+                                     .collect();
+
+            rendered.push_str(&format!("\n{} |\n{} | {}\n{} | {}{}",
+                                       gutter,
+                                       line,
+                                       text,
+                                       gutter,
+                                       indent,
+                                       iter::repeat('^').take(self.width).collect::<String>()));
+        }
+
+        rendered
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+fn error_at(path: &[&str], msg: &str) -> Box<Error> {
+    Box::new(ConfigError::new(msg, path))
+}
+
+pub type Vars = HashMap<String, String>;
+
+// What the configuration can refer to besides itself: the project and
+// branch for variables, ~/.ssh/config for host defaults, and whether the
+// inventory command should be asked for more hosts.
+pub struct Context {
+    pub project: String,
+    pub branch: Option<String>,
+    pub ssh: SshConfig,
+    pub inventory: bool,
+}
+
+// Replaces ${env:NAME}, ${project}, ${git.branch}, ${host.*} and the names
+// defined under "vars". Anything else in ${...} is left alone, so that
+// build commands can still use shell variables. "$${" is written out as a
+// literal "${", for when a shell variable has one of those names.
+fn interpolate(text: &str, vars: &Vars) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start]);
+            result.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        let name = &rest[start + 2..end];
+        result.push_str(&rest[..start]);
+
+        if name.starts_with("env:") {
+            let value = try!(env::var(&name[4..]).map_err(|_| {
+                format!("environment variable \"{}\" is not set", &name[4..])
+            }));
+            result.push_str(&value);
+        } else if let Some(value) = vars.get(name) {
+            result.push_str(value);
+        } else if name == "project" || name.starts_with("host.") || name.starts_with("git.") {
+            return Err(format!("variable \"{}\" is not defined", name));
+        } else {
+            result.push_str(&rest[start..end + 1]);
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn parse_vars(value: &Value, context: &Context) -> Result<Vars, Box<Error>> {
+    let mut builtins = Vars::new();
+    builtins.insert("project".into(), context.project.clone());
+
+    if let Some(ref branch) = context.branch {
+        builtins.insert("git.branch".into(), branch.clone());
+    }
+
+    let table = match *value {
+        Value::Missing => return Ok(builtins),
+        Value::Table(ref table) => table,
+        _ => return Err(error_at(&["vars"], "invalid \"vars\" configuration")),
+    };
+
+    let mut vars = builtins.clone();
+
+    for (name, value) in table {
+        let name = name.as_str();
+
+        if name == "project" || name.contains('.') || name.contains(':') {
+            return Err(error_at(&["vars", name],
+                                &format!("\"{}\" can't be used as a variable name", name)));
         }
+
+        let msg = format!("invalid value for \"{}\" in \"vars\"", name);
+        let value = try!(value.as_str().ok_or(error_at(&["vars", name], &msg)));
+        let value = try!(interpolate(value, &builtins).map_err(|reason| {
+            error_at(&["vars", name], &format!("{}: {}", msg, reason))
+        }));
+
+        vars.insert(name.into(), value);
     }
+
+    Ok(vars)
 }
 
-fn get_optional_str(hash: &Hash, host: &str, name: &str) -> Result<Option<String>, Box<Error>> {
-    let key = Yaml::String(name.into());
+fn get_optional_str(table: &Table,
+                    host: &str,
+                    name: &str,
+                    vars: &Vars)
+                    -> Result<Option<String>, Box<Error>> {
+    let msg = format!("invalid value for \"{}\" in host \"{}\"", name, host);
 
-    match hash.get(&key) {
+    match table.get(name) {
         None => Ok(None),
-        Some(&Yaml::String(ref value)) => Ok(Some(value.to_string())),
-        Some(_) => Err(format!("invalid value for \"{}\" in host \"{}\"", name, host).into()),
+        Some(&Value::String(ref value)) => {
+            interpolate(value, vars)
+                .map(Some)
+                .map_err(|reason| error_at(&["hosts", host, name], &format!("{}: {}", msg, reason)))
+        }
+        Some(_) => Err(error_at(&["hosts", host, name], &msg)),
     }
 }
 
-fn get_str(hash: &Hash, host: &str, name: &str) -> Result<String, Box<Error>> {
-    match get_optional_str(hash, host, name) {
+fn missing_value(host: &str, name: &str) -> Box<Error> {
+    error_at(&["hosts", host],
+             &format!("missing value for \"{}\" in host \"{}\"", name, host))
+}
+
+fn get_str(table: &Table, host: &str, name: &str, vars: &Vars) -> Result<String, Box<Error>> {
+    match get_optional_str(table, host, name, vars) {
         Ok(Some(string)) => Ok(string),
         Err(err) => Err(err),
-        _ => Err(format!("missing value for \"{}\" in host \"{}\"", name, host).into()),
+        _ => Err(missing_value(host, name)),
     }
 }
 
-fn parse_host(name: &str, yaml: &Yaml) -> Result<Host, Box<Error>> {
-    let hash = try!(yaml.as_hash().ok_or(format!("invalid configuration for host \"{}\"", name)));
+fn get_port(table: &Table, host: &str) -> Result<Option<u16>, Box<Error>> {
+    match table.get("port") {
+        None => Ok(None),
+        Some(&Value::Integer(port)) if port > 0 && port <= u16::max_value() as i64 => {
+            Ok(Some(port as u16))
+        }
+        Some(_) => {
+            Err(error_at(&["hosts", host, "port"],
+                         &format!("invalid value for \"port\" in host \"{}\"", host)))
+        }
+    }
+}
+
+fn parse_string_list(value: &Value,
+                     path: &[&str],
+                     err_msg: &str)
+                     -> Result<Vec<String>, Box<Error>> {
+    if let Some(value) = value.as_str() {
+        return Ok(vec![value.into()]);
+    }
+
+    if let Some(values) = value.as_vec() {
+        let mut result = Vec::new();
+
+        for value in values {
+            result.push(try!(value.as_str().ok_or(error_at(path, err_msg))).into());
+        }
 
-    // TODO: should complain on unknown keys
+        return Ok(result);
+    }
+
+    Err(error_at(path, err_msg))
+}
+
+fn parse_artifacts(value: &Value,
+                   default: &[String],
+                   path: &[&str],
+                   err_msg: &str)
+                   -> Result<Vec<String>, Box<Error>> {
+    if value.is_missing() {
+        return Ok(default.to_vec());
+    }
+
+    parse_string_list(value, path, err_msg)
+}
+
+fn interpolate_all(values: &[String],
+                   vars: &Vars,
+                   path: &[&str],
+                   msg: &str)
+                   -> Result<Vec<String>, Box<Error>> {
+    let mut result = Vec::new();
+
+    for value in values {
+        result.push(try!(interpolate(value, vars)
+                             .map_err(|reason| error_at(path, &format!("{}: {}", msg, reason)))));
+    }
+
+    Ok(result)
+}
+
+// Anything left out that ~/.ssh/config has for the host is taken from there,
+// looked up the way ssh would, by the address given to it.
+fn parse_host(name: &str,
+              value: &Value,
+              artifacts: &[String],
+              vars: &Vars,
+              ssh: &SshConfig)
+              -> Result<Host, Box<Error>> {
+    let msg = format!("invalid configuration for host \"{}\"", name);
+    let table = try!(value.as_table().ok_or(error_at(&["hosts", name], &msg)));
+
+    let mut vars = vars.clone();
+    vars.insert("host.name".into(), name.into());
+
+    let artifacts_msg = format!("\"artifacts\" in host \"{}\" must be a string or an array of \
+                                 strings",
+                                name);
+    let artifacts_path = ["hosts", name, "artifacts"];
+    let artifacts = try!(parse_artifacts(&value["artifacts"],
+                                         artifacts,
+                                         &artifacts_path,
+                                         &artifacts_msg));
+
+    let host = try!(get_optional_str(table, &name, "host", &vars));
+    let ssh = ssh.lookup(host.as_ref().map_or(name, |host| host.as_str()));
+
+    let user = match try!(get_optional_str(table, &name, "user", &vars)) {
+        Some(user) => user,
+        None => try!(ssh.user.ok_or(missing_value(name, "user"))),
+    };
+
+    let identity_file = try!(get_optional_str(table, &name, "identity_file", &vars));
 
     Ok(Host {
         name: name.into(),
-        user: try!(get_str(&hash, &name, "user")),
-        build_dir: try!(get_str(&hash, &name, "build_dir")),
-        host: try!(get_optional_str(&hash, &name, "host")),
-        identity_file: try!(get_optional_str(&hash, &name, "identity_file")),
+        user: user,
+        build_dir: try!(get_str(table, &name, "build_dir", &vars)),
+        host: host,
+        port: try!(get_port(table, &name)).or(ssh.port),
+        identity_file: identity_file.or(ssh.identity_file),
+        artifacts: try!(interpolate_all(&artifacts,
+                                        &vars,
+                                        &artifacts_path,
+                                        &format!("invalid \"artifacts\" in host \"{}\"", name))),
+        build: Vec::new(),
     })
 }
 
-fn parse_hosts(yaml: &Yaml) -> Result<Hosts, Box<Error>> {
-    if yaml.is_badvalue() {
+// Build commands can refer to the host they run on, so each host gets its
+// own copy.
+fn host_commands(host: &Host, build: &[String], vars: &Vars) -> Result<Vec<String>, Box<Error>> {
+    let mut vars = vars.clone();
+    vars.insert("host.name".into(), host.name.clone());
+    vars.insert("host.host".into(), host.host().into());
+    vars.insert("host.user".into(), host.user.clone());
+    vars.insert("host.build_dir".into(), host.build_dir.clone());
+
+    interpolate_all(build,
+                    &vars,
+                    &["build"],
+                    &format!("invalid \"build\" command for host \"{}\"", host.name))
+}
+
+// Merges a host's settings over those of the host it extends, and in the
+// end over the defaults, so that required values can come from any of them.
+fn resolve_host(hosts: &Table,
+                name: &str,
+                defaults: &Table,
+                seen: &mut Vec<String>)
+                -> Result<Table, Box<Error>> {
+    if seen.iter().any(|seen| seen == name) {
+        return Err(error_at(&["hosts", &seen[0], "extends"],
+                            &format!("host \"{}\" extends itself ({} -> {})",
+                                     seen[0],
+                                     seen.join(" -> "),
+                                     name)));
+    }
+
+    seen.push(name.into());
+
+    let table = try!(hosts[name]
+                         .as_table()
+                         .ok_or(error_at(&["hosts", name],
+                                         &format!("invalid configuration for host \"{}\"", name))));
+
+    let mut merged = match table.get("extends") {
+        None => defaults.clone(),
+        Some(&Value::String(ref parent)) if hosts.contains_key(parent) => {
+            try!(resolve_host(hosts, parent, defaults, seen))
+        }
+        Some(&Value::String(ref parent)) => {
+            return Err(error_at(&["hosts", name, "extends"],
+                                &format!("host \"{}\" extends unknown host \"{}\"",
+                                         name,
+                                         parent)))
+        }
+        Some(_) => {
+            return Err(error_at(&["hosts", name, "extends"],
+                                &format!("invalid value for \"extends\" in host \"{}\"", name)))
+        }
+    };
+
+    for (key, value) in table {
+        if key != "extends" {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    Ok(merged)
+}
+
+fn parse_hosts(value: &Value,
+               defaults: &Value,
+               artifacts: &[String],
+               vars: &Vars,
+               ssh: &SshConfig)
+               -> Result<Hosts, Box<Error>> {
+    if value.is_missing() {
         return Err("missing \"hosts\" configuration".into());
     }
 
-    let hash = try!(yaml.as_hash().ok_or("invalid \"hosts\" configuration"));
+    let table = try!(value.as_table()
+                          .ok_or(error_at(&["hosts"], "invalid \"hosts\" configuration")));
+
+    let defaults = match *defaults {
+        Value::Missing => Table::new(),
+        Value::Table(ref defaults) => defaults.clone(),
+        _ => return Err(error_at(&["defaults"], "invalid \"defaults\" configuration")),
+    };
+
     let mut hosts = Hosts::new();
 
-    for (key, value) in hash {
-        let name = try!(key.as_str().ok_or("\"hosts\" keys must be strings"));
-        hosts.insert(name.into(), try!(parse_host(name, value)));
+    for name in table.keys() {
+        let merged = try!(resolve_host(table, name, &defaults, &mut Vec::new()));
+        hosts.insert(name.clone(),
+                     try!(parse_host(name, &Value::Table(merged), artifacts, vars, ssh)));
     }
 
     Ok(hosts)
 }
 
-fn parse_build(yaml: &Yaml) -> Result<Vec<String>, Box<Error>> {
-    if yaml.is_badvalue() {
+fn parse_build(value: &Value) -> Result<Vec<String>, Box<Error>> {
+    if value.is_missing() {
         return Err("missing \"build\" configuration".into());
     }
 
-    if let Some(cmd) = yaml.as_str() {
-        return Ok(vec![cmd.into()]);
+    parse_string_list(value,
+                      &["build"],
+                      "\"build\" configuration must be a string or an array of strings")
+}
+
+fn get_gc_value(table: &Table, name: &str, default: u64) -> Result<u64, Box<Error>> {
+    match table.get(name) {
+        None => Ok(default),
+        Some(&Value::Integer(value)) if value >= 0 => Ok(value as u64),
+        Some(_) => {
+            Err(error_at(&["gc", name],
+                         &format!("invalid value for \"{}\" in \"gc\" configuration", name)))
+        }
+    }
+}
+
+fn parse_gc(value: &Value) -> Result<Gc, Box<Error>> {
+    let defaults = Gc::default();
+
+    if value.is_missing() {
+        return Ok(defaults);
     }
 
-    let err_msg = "\"build\" configuration must be a string or an array of strings";
+    let table = try!(value.as_table().ok_or(error_at(&["gc"], "invalid \"gc\" configuration")));
 
-    if let Some(cmds) = yaml.as_vec() {
-        let mut result = Vec::new();
+    Ok(Gc {
+        max_history: try!(get_gc_value(table, "max_history", defaults.max_history)),
+        auto: try!(get_gc_value(table, "auto", defaults.auto)),
+    })
+}
+
+fn parse_shadow_dir(value: &Value) -> Result<PathBuf, Box<Error>> {
+    if value.is_missing() {
+        return Ok(PathBuf::from(".bran"));
+    }
+
+    match value.as_str() {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => Err(error_at(&["shadow_dir"], "\"shadow_dir\" configuration must be a string")),
+    }
+}
 
-        for cmd in cmds {
-            result.push(try!(cmd.as_str().ok_or(err_msg)).into());
+// Patterns are a list, so their errors point at the "patterns" key.
+const PATTERNS_PATH: [&'static str; 2] = ["diagnostics", "patterns"];
+
+fn parse_pattern(value: &Value) -> Result<Matcher, Box<Error>> {
+    let regex = try!(value["regex"]
+                         .as_str()
+                         .ok_or(error_at(&PATTERNS_PATH,
+                                         "each \"diagnostics\" pattern must have a \"regex\" \
+                                          string")));
+
+    let severity = match value["severity"] {
+        Value::Missing => None,
+        Value::String(ref name) => {
+            let msg = format!("invalid severity \"{}\" in \"diagnostics\" pattern", name);
+            Some(try!(Severity::parse(name).ok_or(error_at(&PATTERNS_PATH, &msg))))
         }
+        _ => {
+            return Err(error_at(&PATTERNS_PATH, "invalid severity in \"diagnostics\" pattern"))
+        }
+    };
 
-        return Ok(result);
+    Matcher::new(regex, severity).map_err(|err| {
+        error_at(&PATTERNS_PATH,
+                 &format!("invalid regex \"{}\" in \"diagnostics\": {}", regex, err))
+    })
+}
+
+fn parse_diagnostics(value: &Value) -> Result<DiagnosticsConfig, Box<Error>> {
+    let defaults = DiagnosticsConfig::default();
+
+    if value.is_missing() {
+        return Ok(defaults);
+    }
+
+    try!(value.as_table()
+              .ok_or(error_at(&["diagnostics"], "invalid \"diagnostics\" configuration")));
+
+    let presets = match value["presets"] {
+        Value::Missing => PRESETS.iter().map(|name| name.to_string()).collect(),
+        ref presets => {
+            try!(parse_string_list(presets,
+                                   &["diagnostics", "presets"],
+                                   "\"presets\" in \"diagnostics\" must be a string or an \
+                                    array of strings"))
+        }
+    };
+
+    let mut matchers = Vec::new();
+
+    for name in &presets {
+        let msg = format!("unknown \"diagnostics\" preset \"{}\"", name);
+        matchers.push(try!(Matcher::preset(name)
+                               .ok_or(error_at(&["diagnostics", "presets"], &msg))));
+    }
+
+    if let Some(patterns) = value["patterns"].as_vec() {
+        for pattern in patterns {
+            matchers.push(try!(parse_pattern(pattern)));
+        }
+    } else if !value["patterns"].is_missing() {
+        return Err(error_at(&PATTERNS_PATH, "\"patterns\" in \"diagnostics\" must be an array"));
+    }
+
+    let max_errors = match value["max_errors"] {
+        Value::Missing => defaults.max_errors,
+        Value::Integer(value) if value >= 0 => value as usize,
+        _ => {
+            return Err(error_at(&["diagnostics", "max_errors"],
+                                "invalid value for \"max_errors\" in \"diagnostics\""))
+        }
+    };
+
+    Ok(DiagnosticsConfig {
+        matchers: matchers,
+        max_errors: max_errors,
+    })
+}
+
+// Known keys at each level of the configuration. Keys starting with "x-"
+// are left alone everywhere, so that newer settings don't break older
+// versions.
+const TOP_LEVEL_KEYS: [&'static str; 10] = ["include",
+                                            "vars",
+                                            "hosts",
+                                            "inventory_command",
+                                            "defaults",
+                                            "build",
+                                            "gc",
+                                            "shadow_dir",
+                                            "artifacts",
+                                            "diagnostics"];
+const HOST_KEYS: [&'static str; 7] = ["user",
+                                      "build_dir",
+                                      "host",
+                                      "port",
+                                      "identity_file",
+                                      "artifacts",
+                                      "extends"];
+const DEFAULTS_KEYS: [&'static str; 5] = ["user",
+                                          "build_dir",
+                                          "port",
+                                          "identity_file",
+                                          "artifacts"];
+const INVENTORY_KEYS: [&'static str; 1] = ["hosts"];
+const GC_KEYS: [&'static str; 2] = ["max_history", "auto"];
+const DIAGNOSTICS_KEYS: [&'static str; 3] = ["presets", "max_errors", "patterns"];
+const PATTERN_KEYS: [&'static str; 2] = ["regex", "severity"];
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == b_char { 0 } else { 1 };
+            current.push(*[previous[j + 1] + 1, current[j] + 1, substitution]
+                              .iter()
+                              .min()
+                              .unwrap());
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// Short keys only get a suggestion one edit away, since two edits turn
+// "host" into "port".
+fn suggest(key: &str, known: &[&'static str]) -> Option<&'static str> {
+    let limit = ::std::cmp::max(1, (key.len() + 1) / 3);
+
+    known.iter()
+         .map(|candidate| (edit_distance(key, candidate), *candidate))
+         .filter(|&(distance, _)| distance <= limit)
+         .min()
+         .map(|(_, candidate)| candidate)
+}
+
+// Finds where a key starts in the source, plain or quoted. In YAML and JSON
+// a key is followed by a colon, in TOML by an equals sign, or by a dot or a
+// bracket when it's part of a dotted key or a table header.
+fn find_key(source: &str, format: Format, key: &str) -> Option<usize> {
+    let candidates = [key.to_string(), format!("\"{}\"", key), format!("'{}'", key)];
+    let mut found = None;
+
+    for candidate in &candidates {
+        let mut start = 0;
+
+        while let Some(index) = source[start..].find(candidate.as_str()) {
+            let at = start + index;
+            let end = at + candidate.len();
+
+            let before = source[..at].chars().next_back();
+            let starts_key = before.map_or(true, |c| {
+                c.is_whitespace() || c == '{' || c == ',' || c == '-' ||
+                (format == Format::Toml && (c == '[' || c == '.'))
+            });
+
+            let after = source[end..].trim_left_matches(' ');
+            let ends_key = match format {
+                Format::Toml => after.starts_with('=') || after.starts_with('.') ||
+                                after.starts_with(']'),
+                _ => after.starts_with(':'),
+            };
+
+            if starts_key && ends_key {
+                found = Some(found.map_or(at, |found: usize| ::std::cmp::min(found, at)));
+                break;
+            }
+
+            start = end;
+        }
+    }
+
+    found
+}
+
+// Returns the line and column (both from 1) of the last key in a path such
+// as ["hosts", "foo", "user"], by looking for each key after the previous
+// one. That's not a parser, but it's right for the way configuration files
+// are written in practice.
+fn locate(source: &str, format: Format, path: &[&str]) -> Option<(usize, usize)> {
+    let mut offset = 0;
+    let mut position = None;
+
+    for key in path {
+        let at = match find_key(&source[offset..], format, key) {
+            Some(found) => offset + found,
+            None => return None,
+        };
+
+        position = Some(at);
+        offset = at + key.len();
+    }
+
+    position.map(|at| {
+        let before = &source[..at];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, column)
+    })
+}
+
+fn check_keys(source: &Source,
+              value: &Value,
+              known: &[&'static str],
+              path: &[&str],
+              context: &str)
+              -> Result<(), Box<Error>> {
+    let table = match value.as_table() {
+        Some(table) => table,
+        None => return Ok(()),
+    };
+
+    // The table is sorted by key, so report the unknown key that comes first
+    // in the file.
+    let mut unknown = Vec::new();
+
+    for key in table.keys() {
+        let key = key.as_str();
+
+        if key.starts_with("x-") || known.contains(&key) {
+            continue;
+        }
+
+        let mut key_path = path.to_vec();
+        key_path.push(key);
+
+        let position = source.locate(&key_path).unwrap_or((usize::max_value(), 0));
+        unknown.push((position, key));
+    }
+
+    let (position, key) = match unknown.into_iter().min() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+
+    let mut msg = format!("unknown key \"{}\"{}", key, context);
+
+    if let Some(suggestion) = suggest(key, known) {
+        msg.push_str(&format!(", did you mean \"{}\"?", suggestion));
+    }
+
+    let mut key_path = path.to_vec();
+    key_path.push(key);
+
+    let mut error = ConfigError::new(&msg, &key_path);
+    if position.0 != usize::max_value() {
+        error.position = Some(position);
+    }
+
+    Err(Box::new(error))
+}
+
+fn validate(source: &Source) -> Result<(), Box<Error>> {
+    let settings = &source.settings;
+    try!(check_keys(source, settings, &TOP_LEVEL_KEYS, &[], ""));
+
+    if let Some(hosts) = settings["hosts"].as_table() {
+        for (name, host) in hosts {
+            try!(check_keys(source,
+                            host,
+                            &HOST_KEYS,
+                            &["hosts", name],
+                            &format!(" in host \"{}\"", name)));
+        }
+    }
+
+    try!(check_keys(source,
+                    &settings["defaults"],
+                    &DEFAULTS_KEYS,
+                    &["defaults"],
+                    " in \"defaults\""));
+    try!(check_keys(source, &settings["gc"], &GC_KEYS, &["gc"], " in \"gc\" configuration"));
+
+    let diagnostics = &settings["diagnostics"];
+    try!(check_keys(source,
+                    diagnostics,
+                    &DIAGNOSTICS_KEYS,
+                    &["diagnostics"],
+                    " in \"diagnostics\""));
+
+    if let Some(patterns) = diagnostics["patterns"].as_vec() {
+        for pattern in patterns {
+            try!(check_keys(source,
+                            pattern,
+                            &PATTERN_KEYS,
+                            &["diagnostics", "patterns"],
+                            " in \"diagnostics\" pattern"));
+        }
+    }
+
+    Ok(())
+}
+
+fn object(entries: Vec<(&str, Value)>) -> Value {
+    let mut table = Table::new();
+
+    for (key, value) in entries {
+        insert(&mut table, key, value);
+    }
+
+    Value::Table(table)
+}
+
+fn strings(values: &[&str]) -> Value {
+    Value::Array(values.iter().map(|value| Value::String(value.to_string())).collect())
+}
+
+fn described(kind: &str, description: &str) -> Value {
+    object(vec![("type", Value::String(kind.into())),
+                ("description", Value::String(description.into()))])
+}
+
+fn count(description: &str) -> Value {
+    object(vec![("type", Value::String("integer".into())),
+                ("minimum", Value::Integer(0)),
+                ("description", Value::String(description.into()))])
+}
+
+// Lists can also be given as a single string.
+fn string_or_list(item: Value, description: &str) -> Value {
+    let list = object(vec![("type", Value::String("array".into())), ("items", item.clone())]);
+
+    object(vec![("oneOf", Value::Array(vec![item, list])),
+                ("description", Value::String(description.into()))])
+}
+
+fn paths(description: &str) -> Value {
+    string_or_list(object(vec![("type", Value::String("string".into()))]), description)
+}
+
+// An object with only the given keys, besides "x-" ones.
+fn keys(properties: Vec<(&str, Value)>, description: &str) -> Table {
+    let mut table = Table::new();
+    insert(&mut table, "type", Value::String("object".into()));
+    insert(&mut table, "description", Value::String(description.into()));
+    insert(&mut table, "properties", object(properties));
+    insert(&mut table, "patternProperties", object(vec![("^x-", object(Vec::new()))]));
+    insert(&mut table, "additionalProperties", Value::Boolean(false));
+    table
+}
+
+fn host_schema(host: bool, description: &str) -> Value {
+    let port = object(vec![("type", Value::String("integer".into())),
+                           ("minimum", Value::Integer(1)),
+                           ("maximum", Value::Integer(u16::max_value() as i64)),
+                           ("description", Value::String("SSH port".into()))]);
+
+    let mut properties = vec![("user", described("string", "User to log in as")),
+                              ("build_dir", described("string", "Directory to build in")),
+                              ("port", port),
+                              ("identity_file",
+                               described("string", "SSH private key to log in with")),
+                              ("artifacts",
+                               paths("Files to fetch after a build, relative to build_dir"))];
+
+    if host {
+        properties.push(("host", described("string", "Address of the host, if not its name")));
+        properties.push(("extends", described("string", "Host to inherit settings from")));
     }
 
-    return Err(err_msg.into());
+    Value::Table(keys(properties, description))
 }
 
-fn parse_config(contents: &str) -> Result<Config, Box<Error>> {
-    let yaml = try!(YamlLoader::load_from_str(&contents));
+// A JSON Schema for the configuration, for editors and linters. Tests keep
+// its keys the same as the ones validate() knows.
+pub fn schema() -> Value {
+    let severity = object(vec![("enum", strings(&SEVERITIES)),
+                               ("description", Value::String("Severity of every match".into()))]);
+
+    let mut pattern = keys(vec![("regex",
+                                 described("string",
+                                           "Regex with a \"message\" group, and optionally \
+                                            \"file\", \"line\", \"column\" and \"severity\"")),
+                                ("severity", severity)],
+                           "A custom diagnostic pattern");
+    insert(&mut pattern, "required", strings(&["regex"]));
+
+    let patterns = object(vec![("type", Value::String("array".into())),
+                               ("items", Value::Table(pattern)),
+                               ("description",
+                                Value::String("Custom diagnostic patterns".into()))]);
+
+    let preset = object(vec![("enum", strings(&PRESETS))]);
 
-    if yaml.len() == 0 {
+    let diagnostics = keys(vec![("presets", string_or_list(preset, "Built-in patterns to use")),
+                                ("max_errors", count("Errors to show for each host")),
+                                ("patterns", patterns)],
+                           "How to find errors and warnings in build output");
+
+    let gc = keys(vec![("max_history",
+                        count("Commits after which the shadow repository's history is squashed \
+                               into one, 0 to never squash")),
+                       ("auto", count("Loose objects to allow before packing, 0 for no limit"))],
+                  "Garbage collection of the shadow repository");
+
+    let vars = object(vec![("type", Value::String("object".into())),
+                           ("additionalProperties",
+                            object(vec![("type", Value::String("string".into()))])),
+                           ("description",
+                            Value::String("Variables to use as ${name} in other values".into()))]);
+
+    let hosts = object(vec![("type", Value::String("object".into())),
+                            ("additionalProperties", host_schema(true, "A host to build on")),
+                            ("description", Value::String("Hosts to build on, by name".into()))]);
+
+    let mut settings = keys(vec![("include",
+                                  paths("Files to merge in before this one, relative to it")),
+                                 ("vars", vars),
+                                 ("hosts", hosts),
+                                 ("inventory_command",
+                                  described("string",
+                                            "Command whose JSON output has more \"hosts\"")),
+                                 ("defaults", host_schema(false, "Settings shared by all hosts")),
+                                 ("build", paths("Commands to run on each host")),
+                                 ("gc", Value::Table(gc)),
+                                 ("shadow_dir",
+                                  described("string", "Where to keep the shadow repository")),
+                                 ("artifacts", paths("Files to fetch from every host")),
+                                 ("diagnostics", Value::Table(diagnostics))],
+                            "bran configuration");
+
+    insert(&mut settings,
+           "$schema",
+           Value::String("http://json-schema.org/draft-04/schema#".into()));
+    insert(&mut settings, "title", Value::String("bran configuration".into()));
+    Value::Table(settings)
+}
+
+fn parse_settings(settings: &Value, context: &Context) -> Result<Config, Box<Error>> {
+    if settings.is_missing() {
         return Err("no configuration found".into());
     }
 
-    let settings = &yaml[0];
-    let hosts = try!(parse_hosts(&settings["hosts"]));
+    let vars = try!(parse_vars(&settings["vars"], context));
+
+    let artifacts = try!(parse_artifacts(&settings["artifacts"],
+                                         &[],
+                                         &["artifacts"],
+                                         "\"artifacts\" configuration must be a string or an \
+                                          array of strings"));
+    let mut hosts = try!(parse_hosts(&settings["hosts"],
+                                     &settings["defaults"],
+                                     &artifacts,
+                                     &vars,
+                                     &context.ssh));
     let build = try!(parse_build(&settings["build"]));
 
-    Ok(Config::new(hosts, build))
+    for host in hosts.values_mut() {
+        host.build = try!(host_commands(host, &build, &vars));
+    }
+
+    let gc = try!(parse_gc(&settings["gc"]));
+    let shadow_dir = try!(parse_shadow_dir(&settings["shadow_dir"]));
+    let diagnostics = try!(parse_diagnostics(&settings["diagnostics"]));
+
+    Ok(Config::new(hosts, build, gc, shadow_dir, diagnostics))
+}
+
+fn config_error(err: Box<Error>) -> ConfigError {
+    match err.downcast::<ConfigError>() {
+        Ok(error) => *error,
+        Err(err) => ConfigError::new(err.description(), &[]),
+    }
+}
+
+// A configuration file, kept along with its text so that errors can point
+// into it. An empty file has no settings (a missing value).
+struct Source {
+    name: String,
+    format: Format,
+    contents: String,
+    settings: Value,
+}
+
+impl Source {
+    fn load(name: &str, format: Format, contents: String) -> Result<Source, ConfigError> {
+        let settings = match value::parse(format, &contents) {
+            Ok(settings) => settings,
+            Err(err) => {
+                return Err(ConfigError::from_parse_error(err).place_in(name, format, &contents))
+            }
+        };
+
+        Ok(Source {
+            name: name.into(),
+            format: format,
+            contents: contents,
+            settings: settings,
+        })
+    }
+
+    fn error(&self, err: Box<Error>) -> ConfigError {
+        config_error(err).place_in(&self.name, self.format, &self.contents)
+    }
+
+    fn locate(&self, path: &[&str]) -> Option<(usize, usize)> {
+        locate(&self.contents, self.format, path)
+    }
+}
+
+// Settings from a later file replace those of earlier ones, except that
+// mappings are merged key by key.
+fn merge(base: Value, over: &Value) -> Value {
+    match (base, over) {
+        (Value::Table(mut base), &Value::Table(ref over)) => {
+            for (key, value) in over {
+                let merged = match base.remove(key) {
+                    Some(existing) => merge(existing, value),
+                    None => value.clone(),
+                };
+
+                base.insert(key.clone(), merged);
+            }
+
+            Value::Table(base)
+        }
+        (base, &Value::Missing) => base,
+        (_, over) => over.clone(),
+    }
 }
 
-fn parse_config_file(name: &str) -> Result<Config, Box<Error>> {
-    let mut file = try!(File::open(name));
+// Runs the inventory command, if there is one, for its output: JSON with a
+// "hosts" object in the same form as in the configuration.
+fn run_inventory(command: &Value) -> Result<Option<String>, Box<Error>> {
+    let command = match *command {
+        Value::Missing => return Ok(None),
+        Value::String(ref command) => command,
+        _ => {
+            return Err(error_at(&["inventory_command"],
+                                "\"inventory_command\" configuration must be a string"))
+        }
+    };
+
+    let output = try!(Command::new("sh")
+                          .arg("-c")
+                          .arg(command)
+                          .stdin(Stdio::null())
+                          .stderr(Stdio::inherit())
+                          .output()
+                          .map_err(|err| {
+                              error_at(&["inventory_command"],
+                                       &format!("failed to run \"inventory_command\": {}", err))
+                          }));
+
+    if !output.status.success() {
+        let msg = match output.status.code() {
+            Some(code) => format!("\"inventory_command\" failed with exit code {}", code),
+            None => "\"inventory_command\" was killed".into(),
+        };
+        return Err(error_at(&["inventory_command"], &msg));
+    }
+
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+const INVENTORY: &'static str = "inventory_command output";
+
+// Each file is checked on its own, so that unknown keys are reported where
+// they are, then all of them are merged in order. Hosts from the inventory
+// go under all of them, so that the files can adjust those hosts.
+fn parse_sources(sources: &[Source], context: &Context) -> Result<Config, ConfigError> {
+    let mut settings = Value::Missing;
+
+    for source in sources {
+        try!(validate(source).map_err(|err| source.error(err)));
+        settings = merge(settings, &source.settings);
+    }
+
+    // Left out, the inventory counts as having no hosts.
+    let output = if context.inventory {
+        try!(run_inventory(&settings["inventory_command"])
+                 .map_err(|err| config_error(err).place(sources.iter())))
+    } else if settings["inventory_command"].is_missing() {
+        None
+    } else {
+        Some("{\"hosts\": {}}".into())
+    };
+
+    let inventory = match output {
+        Some(output) => Some(try!(Source::load(INVENTORY, Format::Json, output))),
+        None => None,
+    };
+
+    if let Some(ref inventory) = inventory {
+        try!(check_keys(inventory,
+                        &inventory.settings,
+                        &INVENTORY_KEYS,
+                        &[],
+                        " in \"inventory_command\" output")
+                 .and_then(|_| validate(inventory))
+                 .map_err(|err| inventory.error(err)));
+        settings = merge(inventory.settings.clone(), &settings);
+    }
+
+    parse_settings(&settings, context)
+        .map_err(|err| config_error(err).place(inventory.iter().chain(sources)))
+}
+
+// Configuration files are named without their extension, which can be any
+// of value::EXTENSIONS.
+const CONFIG: &'static str = "bran";
+pub const LOCAL_CONFIG: &'static str = "bran.local";
+
+// Guards against files including each other.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+fn read_source(path: &Path) -> Result<Source, Box<Error>> {
+    let mut file = try!(File::open(path));
 
     let mut contents = String::new();
     try!(file.read_to_string(&mut contents));
 
-    parse_config(&contents)
+    Source::load(&path.to_string_lossy(), Format::from_path(path), contents)
+        .map_err(|err| Box::new(err) as Box<Error>)
+}
+
+// Adds a file to the sources after the files it includes, so that it
+// overrides them. Includes are relative to the including file.
+fn add_source(source: Source,
+              dir: &Path,
+              sources: &mut Vec<Source>,
+              depth: usize)
+              -> Result<(), ConfigError> {
+    let includes = match source.settings["include"] {
+        Value::Missing => Vec::new(),
+        ref include => {
+            try!(parse_string_list(include,
+                                   &["include"],
+                                   "\"include\" must be a string or an array of strings")
+                     .map_err(|err| source.error(err)))
+        }
+    };
+
+    if !includes.is_empty() && depth == MAX_INCLUDE_DEPTH {
+        return Err(source.error(error_at(&["include"], "includes are nested too deeply")));
+    }
+
+    for include in &includes {
+        let path = dir.join(include);
+
+        let included = match read_source(&path) {
+            Ok(included) => included,
+            Err(err) => {
+                return match err.downcast::<ConfigError>() {
+                    Ok(error) => Err(*error),
+                    Err(err) => {
+                        let msg = format!("failed to read \"{}\": {}", include, err);
+                        Err(source.error(error_at(&["include"], &msg)))
+                    }
+                }
+            }
+        };
+
+        let included_dir = path.parent().unwrap_or(dir).to_path_buf();
+        try!(add_source(included, &included_dir, sources, depth + 1));
+    }
+
+    sources.push(source);
+    Ok(())
+}
+
+fn add_file(path: &Path, sources: &mut Vec<Source>) -> Result<(), ConfigError> {
+    let source = try!(read_source(path).map_err(config_error));
+    add_source(source, path.parent().unwrap_or(Path::new("")), sources, 0)
+}
+
+// Finds the file with the given name in the first format that exists, so
+// bran.yml wins over bran.toml if somehow there are both.
+fn find_config(dir: &Path, name: &str) -> Option<PathBuf> {
+    value::EXTENSIONS.iter()
+                     .map(|extension| dir.join(format!("{}.{}", name, extension)))
+                     .find(|path| path.is_file())
+}
+
+fn user_config() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .and_then(|dir| find_config(&dir.join("bran"), "config"))
 }
 
-pub fn read() -> Result<Config, Box<Error>> {
-    match parse_config_file("bran.yml") {
+// Files are merged in this order, each overriding the ones before it: the
+// project's bran.yml, the user's ~/.config/bran/config.yml, then the
+// developer's own bran.local.yml. Each comes after the files it includes,
+// and each can be YAML, TOML or JSON.
+fn read_sources(root: &Path, user_config: Option<PathBuf>) -> Result<Vec<Source>, ConfigError> {
+    let mut sources = Vec::new();
+
+    match find_config(root, CONFIG) {
+        Some(path) => try!(add_file(&path, &mut sources)),
+        None => return Err(ConfigError::new("no configuration found", &[])),
+    }
+
+    if let Some(path) = user_config {
+        try!(add_file(&path, &mut sources));
+    }
+
+    if let Some(path) = find_config(root, LOCAL_CONFIG) {
+        try!(add_file(&path, &mut sources));
+    }
+
+    Ok(sources)
+}
+
+// Walk up from the current directory to the first one containing bran.yml,
+// bran.yaml, bran.toml or bran.json.
+fn find_root() -> Result<PathBuf, Box<Error>> {
+    let cwd = try!(env::current_dir());
+    let mut dir = Some(cwd.as_path());
+
+    while let Some(candidate) = dir {
+        if find_config(candidate, CONFIG).is_some() {
+            return Ok(candidate.to_path_buf());
+        }
+
+        dir = candidate.parent();
+    }
+
+    Err("Failed to find bran.yml in the current directory or any parent".into())
+}
+
+// Whether there is a configuration to read at all.
+pub fn exists() -> bool {
+    find_root().is_ok()
+}
+
+// Commands that don't talk to the hosts leave the inventory command alone,
+// since it can be slow or need credentials.
+pub fn read(inventory: bool) -> Result<Config, Box<Error>> {
+    let root = try!(find_root());
+
+    // Everything else (the shadow repo, the working tree) is relative to
+    // the project root, so run from there.
+    try!(env::set_current_dir(&root));
+
+    let context = Context {
+        project: root.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+        branch: git::current_branch(&root),
+        ssh: SshConfig::read(),
+        inventory: inventory,
+    };
+
+    let sources = read_sources(Path::new(""), user_config());
+    match sources.and_then(|sources| parse_sources(&sources, &context)) {
         Ok(config) => Ok(config),
-        Err(err) => Err(format!("Failed to read bran.yml: {}", err.description()).into()),
+        Err(err) => Err(format!("Failed to read the configuration: {}", err.render()).into()),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_config;
+    use std::env;
+    use std::error::Error;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use libc;
+    use super::{Config, ConfigError, Context, Source};
+    use diagnostics::GROUPS;
+    use ssh_config::SshConfig;
+    use value::Format;
+
+    fn context() -> Context {
+        Context {
+            project: "winterfell".into(),
+            branch: Some("main".into()),
+            ssh: SshConfig::default(),
+            inventory: true,
+        }
+    }
+
+    fn parse_config(contents: &str) -> Result<Config, ConfigError> {
+        let source = try!(Source::load("bran.yml", Format::Yaml, contents.into()));
+        super::parse_sources(&[source], &context())
+    }
 
     fn check_fail(contents: &str, msg: &str) {
         let config = parse_config(contents);
@@ -185,7 +1521,7 @@ mod tests {
 
     #[test]
     fn fails_when_hosts_missing() {
-        let yaml = "hodor: 1";
+        let yaml = "build: x";
         check_fail(yaml, "missing \"hosts\" configuration");
     }
 
@@ -272,6 +1608,665 @@ mod tests {
         assert_eq!(config.build, ["x", "y", "z"]);
     }
 
+    #[test]
+    fn parses_defaults_and_extends() {
+        let yaml = "
+            defaults:
+                user: a
+                identity_file: b
+                build_dir: c
+            hosts:
+                linux:
+                    host: d
+                linux-debug:
+                    extends: linux
+                    build_dir: e
+                win:
+                    user: f
+            build: x";
+
+        let config = parse_config(yaml).expect("should parse successfully");
+
+        let linux = &config.hosts["linux"];
+        assert_eq!(linux.host, Some("d".into()));
+        assert_eq!(linux.user, "a");
+        assert_eq!(linux.identity_file, Some("b".into()));
+        assert_eq!(linux.build_dir, "c");
+
+        let debug = &config.hosts["linux-debug"];
+        assert_eq!(debug.name, "linux-debug");
+        assert_eq!(debug.host, Some("d".into()));
+        assert_eq!(debug.user, "a");
+        assert_eq!(debug.build_dir, "e");
+
+        let win = &config.hosts["win"];
+        assert_eq!(win.host, None);
+        assert_eq!(win.user, "f");
+        assert_eq!(win.build_dir, "c");
+    }
+
+    #[test]
+    fn fails_when_required_value_not_inherited() {
+        let yaml = "{defaults: {user: a}, hosts: {foo: {}, bar: {extends: foo}}, build: x}";
+        check_fail(yaml, "missing value for \"build_dir\" in host \"bar\"");
+    }
+
+    #[test]
+    fn fails_when_extends_unknown_host() {
+        let yaml = "{hosts: {foo: {extends: bar}}, build: x}";
+        check_fail(yaml, "host \"foo\" extends unknown host \"bar\"");
+    }
+
+    #[test]
+    fn fails_when_extends_is_circular() {
+        let yaml = "{hosts: {a: {extends: b}, b: {extends: a}}, build: x}";
+        check_fail(yaml, "host \"a\" extends itself (a -> b -> a)");
+    }
+
+    #[test]
+    fn fails_when_defaults_invalid() {
+        check_fail("{hosts: {}, build: x, defaults: [1]}", "invalid \"defaults\" configuration");
+        check_fail("{hosts: {}, build: x, defaults: {host: a}}",
+                   "unknown key \"host\" in \"defaults\"");
+        check_fail("{hosts: {}, build: x, defaults: {usr: a}}",
+                   "unknown key \"usr\" in \"defaults\", did you mean \"user\"?");
+    }
+
+    fn load(name: &str, contents: &str) -> super::Source {
+        let format = Format::from_path(super::Path::new(name));
+        super::Source::load(name, format, contents.into()).expect("should load successfully")
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones() {
+        let sources = [load("bran.yml",
+                            "{defaults: {user: c, build_dir: d}, hosts: {foo: {}}, build: x}"),
+                       load("config.yml", "defaults: {user: a, identity_file: b}"),
+                       load("bran.local.yml", "{defaults: {user: e}, build: [y, z]}")];
+
+        let config = super::parse_sources(&sources, &context())
+                         .expect("should parse successfully");
+
+        let foo = &config.hosts["foo"];
+        assert_eq!(foo.user, "e");
+        assert_eq!(foo.identity_file, Some("b".into()));
+        assert_eq!(foo.build_dir, "d");
+        assert_eq!(config.build, ["y", "z"]);
+    }
+
+    #[test]
+    fn user_config_sits_between_project_and_local_files() {
+        let dir = env::temp_dir().join(format!("bran-layers-{}", unsafe { libc::getpid() }));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("user")).unwrap();
+
+        let write = |name: &str, contents: &str| {
+            File::create(dir.join(name)).unwrap().write_all(contents.as_bytes()).unwrap();
+        };
+        write("bran.yml",
+              "{defaults: {user: a, build_dir: b, port: 1}, hosts: {foo: {}}, build: x}");
+        write("user/config.toml", "[defaults]\nuser = \"c\"\nport = 2");
+        write("bran.local.json", "{\"defaults\": {\"port\": 3}}");
+
+        let sources = super::read_sources(&dir, Some(dir.join("user/config.toml")))
+                          .expect("should read successfully");
+        let config = super::parse_sources(&sources, &context())
+                         .expect("should parse successfully");
+        fs::remove_dir_all(&dir).ok();
+
+        let foo = &config.hosts["foo"];
+        assert_eq!(foo.build_dir, "b");
+        assert_eq!(foo.user, "c");
+        assert_eq!(foo.port, Some(3));
+    }
+
+    #[test]
+    fn places_errors_in_the_file_used() {
+        let sources = [load("bran.yml", "{hosts: {foo: {user: a, build_dir: b}}, build: x}"),
+                       load("bran.local.yml", "hosts:\n  foo:\n    user: [1]")];
+
+        let error = super::parse_sources(&sources, &context()).unwrap_err();
+        assert!(error.render().contains(" --> bran.local.yml:3:5"));
+    }
+
+    #[test]
+    fn checks_each_file_for_unknown_keys() {
+        let sources = [load("bran.yml", "{hosts: {}, build: x}"),
+                       load("bran.local.yml", "\nbulid: y")];
+
+        let error = super::parse_sources(&sources, &context()).unwrap_err();
+        assert!(error.render().contains(" --> bran.local.yml:2:1"));
+    }
+
+    #[test]
+    fn parses_every_format_the_same() {
+        let yaml = load("bran.yml",
+                        "
+hosts:
+    linux:
+        user: a
+        build_dir: b
+build: [x, y]
+gc:
+    auto: 0");
+        let toml = load("bran.toml",
+                        "
+build = [\"x\", \"y\"]
+
+[hosts.linux]
+user = \"a\"
+build_dir = \"b\"
+
+[gc]
+auto = 0");
+        let json = load("bran.json",
+                        r#"{
+    "hosts": {"linux": {"user": "a", "build_dir": "b"}},
+    "build": ["x", "y"],
+    "gc": {"auto": 0}
+}"#);
+
+        let resolved: Vec<_> = vec![yaml, toml, json]
+                                   .into_iter()
+                                   .map(|source| {
+                                       super::parse_sources(&[source], &context())
+                                           .expect("should parse successfully")
+                                           .to_value()
+                                   })
+                                   .collect();
+
+        assert_eq!(resolved[0], resolved[1]);
+        assert_eq!(resolved[0], resolved[2]);
+        assert_eq!(resolved[0]["hosts"]["linux"]["build_dir"].as_str(), Some("b"));
+    }
+
+    #[test]
+    fn reports_the_same_errors_in_every_format() {
+        let unknown = [("bran.yml", "hosts: {}\nbulid: x", " --> bran.yml:2:1"),
+                       ("bran.toml", "bulid = \"x\"\n[hosts]\n", " --> bran.toml:1:1"),
+                       ("bran.json", "{\"hosts\": {},\n \"bulid\": \"x\"}", " --> bran.json:2:2")];
+
+        for &(name, contents, position) in &unknown {
+            let error = super::parse_sources(&[load(name, contents)], &context()).unwrap_err();
+            assert_eq!(error.description(), "unknown key \"bulid\", did you mean \"build\"?");
+            assert!(error.render().contains(position), "{}", error.render());
+        }
+
+        let invalid = [("bran.yml",
+                        "hosts:\n  foo:\n    user: a\n    build_dir: [1]\nbuild: x",
+                        " --> bran.yml:4:5"),
+                       ("bran.toml",
+                        "build = \"x\"\n[hosts.foo]\nuser = \"a\"\nbuild_dir = [1]",
+                        " --> bran.toml:4:1"),
+                       ("bran.json",
+                        r#"{"hosts": {"foo": {"user": "a",
+ "build_dir": [1]}}, "build": "x"}"#,
+                        " --> bran.json:2:2")];
+
+        for &(name, contents, position) in &invalid {
+            let error = super::parse_sources(&[load(name, contents)], &context()).unwrap_err();
+            assert_eq!(error.description(), "invalid value for \"build_dir\" in host \"foo\"");
+            assert!(error.render().contains(position), "{}", error.render());
+        }
+    }
+
+    #[test]
+    fn places_syntax_errors_in_every_format() {
+        let toml = super::Source::load("bran.toml", Format::Toml, "build = \n".into());
+        let error = toml.err().expect("should fail to load");
+        assert!(error.render().contains(" --> bran.toml:1:"), "{}", error.render());
+
+        let json = super::Source::load("bran.json", Format::Json, "{\n  \"build\": }".into());
+        let error = json.err().expect("should fail to load");
+        assert!(error.render().contains(" --> bran.json:2:"), "{}", error.render());
+    }
+
+    #[test]
+    fn interpolates_variables() {
+        super::env::set_var("BRAN_TEST_USER", "hodor");
+
+        let yaml = "
+            vars:
+                scratch: /scratch/${env:BRAN_TEST_USER}
+            hosts:
+                linux:
+                    user: ${env:BRAN_TEST_USER}
+                    build_dir: ${scratch}/${project}/${git.branch}
+                    artifacts: out/${host.name}
+            build:
+                - make -C ${host.build_dir} HOST=${host.name}
+                - echo ${HOME} $$ $${project} $$${project}";
+
+        let config = parse_config(yaml).expect("should parse successfully");
+
+        let linux = &config.hosts["linux"];
+        assert_eq!(linux.user, "hodor");
+        assert_eq!(linux.build_dir, "/scratch/hodor/winterfell/main");
+        assert_eq!(linux.artifacts, ["out/linux"]);
+        assert_eq!(linux.build,
+                   ["make -C /scratch/hodor/winterfell/main HOST=linux",
+                    "echo ${HOME} $$ ${project} $${project}"]);
+        assert_eq!(config.build[0], "make -C ${host.build_dir} HOST=${host.name}");
+    }
+
+    #[test]
+    fn escapes_variables() {
+        let yaml = "
+            vars:
+                literal: $${project}
+            hosts:
+                linux:
+                    user: a
+                    build_dir: $${host.name}/${literal}
+            build: echo $${git.branch} $${env:BRAN_TEST_UNSET}";
+
+        let config = parse_config(yaml).expect("should parse successfully");
+
+        let linux = &config.hosts["linux"];
+        assert_eq!(linux.build_dir, "${host.name}/${project}");
+        assert_eq!(linux.build, ["echo ${git.branch} ${env:BRAN_TEST_UNSET}"]);
+    }
+
+    #[test]
+    fn fails_on_undefined_variables() {
+        let yaml = "{hosts: {foo: {user: a, build_dir: '${host.user}'}}, build: x}";
+        check_fail(yaml,
+                   "invalid value for \"build_dir\" in host \"foo\": variable \"host.user\" \
+                    is not defined");
+
+        let yaml = "{hosts: {foo: {user: a, build_dir: b}}, build: '${env:BRAN_TEST_UNSET}'}";
+        check_fail(yaml,
+                   "invalid \"build\" command for host \"foo\": environment variable \
+                    \"BRAN_TEST_UNSET\" is not set");
+
+        let yaml = "{vars: {git.branch: x}, hosts: {}, build: x}";
+        check_fail(yaml, "\"git.branch\" can't be used as a variable name");
+    }
+
+    #[test]
+    fn takes_host_defaults_from_ssh_config() {
+        let yaml = "
+            hosts:
+                linux:
+                    build_dir: a
+                win:
+                    host: win.example.com
+                    user: b
+                    build_dir: c
+                    port: 22
+            build: x";
+
+        let mut context = context();
+        context.ssh = SshConfig::parse("
+Host linux
+    User d
+    Port 2222
+    IdentityFile /keys/linux
+Host *.example.com
+    User e
+    Port 2200");
+
+        let source = super::Source::load("bran.yml", Format::Yaml, yaml.into())
+                         .expect("should load successfully");
+        let config = super::parse_sources(&[source], &context).expect("should parse successfully");
+
+        let linux = &config.hosts["linux"];
+        assert_eq!(linux.user, "d");
+        assert_eq!(linux.port, Some(2222));
+        assert_eq!(linux.identity_file, Some("/keys/linux".into()));
+        assert_eq!(linux.git_ssh_url(), "ssh://d@linux:2222/~/a");
+
+        let win = &config.hosts["win"];
+        assert_eq!(win.user, "b");
+        assert_eq!(win.port, Some(22));
+    }
+
+    #[test]
+    fn fails_when_port_invalid() {
+        let yaml = "{hosts: {foo: {user: a, build_dir: b, port: 65536}}, build: x}";
+        check_fail(yaml, "invalid value for \"port\" in host \"foo\"");
+    }
+
+    #[test]
+    fn adds_hosts_from_inventory_command() {
+        let yaml = r#"
+            defaults:
+                user: a
+            hosts:
+                vm1:
+                    build_dir: /override
+            inventory_command: "echo '{\"hosts\": {\"vm1\": {\"build_dir\": \"b\"},
+                                                 \"vm2\": {\"build_dir\": \"c\"}}}'"
+            build: x"#;
+
+        let config = parse_config(yaml).expect("should parse successfully");
+        assert_eq!(config.hosts.len(), 2);
+        assert_eq!(config.hosts["vm1"].build_dir, "/override");
+        assert_eq!(config.hosts["vm2"].user, "a");
+        assert_eq!(config.hosts["vm2"].build_dir, "c");
+    }
+
+    #[test]
+    fn fails_when_inventory_command_fails() {
+        let yaml = "{hosts: {}, build: x, inventory_command: exit 3}";
+        check_fail(yaml, "\"inventory_command\" failed with exit code 3");
+
+        let yaml = "{build: x, inventory_command: \"echo '{\\\"build\\\": \\\"y\\\"}'\"}";
+        let error = parse_config(yaml).unwrap_err();
+        assert_eq!(error.description(),
+                   "unknown key \"build\" in \"inventory_command\" output");
+        assert!(error.render().contains(" --> inventory_command output:1:2"));
+
+        let yaml = "{build: x, inventory_command: \"echo '{\\\"hosts\\\": {\\\"vm\\\": 1}}'\"}";
+        let error = parse_config(yaml).unwrap_err();
+        assert_eq!(error.description(), "invalid configuration for host \"vm\"");
+        assert!(error.render().contains(" --> inventory_command output:1:12"));
+    }
+
+    #[test]
+    fn leaves_the_inventory_command_alone_when_asked() {
+        let mut context = context();
+        context.inventory = false;
+
+        let source = Source::load("bran.yml",
+                                  Format::Yaml,
+                                  "{build: x, inventory_command: exit 3}".into())
+                         .expect("should load successfully");
+        let config = super::parse_sources(&[source], &context).expect("should parse successfully");
+        assert!(config.hosts.is_empty());
+    }
+
+    #[test]
+    fn parses_gc_defaults() {
+        let yaml = "{hosts: {}, build: abc}";
+        let config = parse_config(yaml).expect("should parse successfully");
+        assert_eq!(config.gc, super::Gc::default());
+    }
+
+    #[test]
+    fn parses_gc() {
+        let yaml = "{hosts: {}, build: abc, gc: {max_history: 5, auto: 0}}";
+        let config = parse_config(yaml).expect("should parse successfully");
+        assert_eq!(config.gc.max_history, 5);
+        assert_eq!(config.gc.auto, 0);
+    }
+
+    #[test]
+    fn fails_when_gc_invalid() {
+        let yaml = "{hosts: {}, build: abc, gc: {auto: -1}}";
+        check_fail(yaml, "invalid value for \"auto\" in \"gc\" configuration");
+    }
+
+    #[test]
+    fn parses_shadow_dir() {
+        let yaml = "{hosts: {}, build: abc, shadow_dir: /tmp/shadow}";
+        let config = parse_config(yaml).expect("should parse successfully");
+        assert_eq!(config.shadow_dir, super::PathBuf::from("/tmp/shadow"));
+    }
+
+    #[test]
+    fn fails_when_shadow_dir_invalid() {
+        let yaml = "{hosts: {}, build: abc, shadow_dir: [1]}";
+        check_fail(yaml, "\"shadow_dir\" configuration must be a string");
+    }
+
+    #[test]
+    fn parses_artifacts() {
+        let yaml = "
+            hosts:
+                linux:
+                    user: a
+                    build_dir: b
+                win:
+                    user: a
+                    build_dir: b
+                    artifacts: out/*.exe
+            build: x
+            artifacts:
+                - target/release/bran
+                - target/release/*.so";
+
+        let config = parse_config(yaml).expect("should parse successfully");
+        assert_eq!(config.hosts["linux"].artifacts,
+                   ["target/release/bran", "target/release/*.so"]);
+        assert_eq!(config.hosts["win"].artifacts, ["out/*.exe"]);
+    }
+
+    #[test]
+    fn fails_when_host_artifacts_invalid() {
+        let yaml = "{hosts: {foo: {user: a, build_dir: b, artifacts: {}}}, build: x}";
+        check_fail(yaml,
+                   "\"artifacts\" in host \"foo\" must be a string or an array of strings");
+    }
+
+    #[test]
+    fn scp_command() {
+        let host = super::Host {
+            name: "westeros".into(),
+            user: "hodor".into(),
+            build_dir: "winterfell".into(),
+            host: None,
+            port: None,
+            identity_file: Some("id_rsa".into()),
+            artifacts: Vec::new(),
+            build: Vec::new(),
+        };
+
+        assert_eq!(host.scp_command("out/bran", "bran-artifacts/westeros/out/bran"),
+                   ["-q",
+                    "-o",
+                    "PreferredAuthentications=publickey",
+                    "-i",
+                    "id_rsa",
+                    "hodor@westeros:'winterfell/out/bran'",
+                    "bran-artifacts/westeros/out/bran"]);
+
+        assert_eq!(host.scp_command("out/it's *.txt", "local")[5],
+                   "hodor@westeros:'winterfell/out/it'\\''s *.txt'");
+    }
+
+    #[test]
+    fn parses_diagnostics() {
+        let yaml = "
+            hosts: {}
+            build: x
+            diagnostics:
+                presets: gcc
+                max_errors: 3
+                patterns:
+                    - regex: '^FAIL: (?P<message>.*)$'
+                    - regex: '^WARN: (?P<message>.*)$'
+                      severity: warning";
+
+        let config = parse_config(yaml).expect("should parse successfully");
+        assert_eq!(config.diagnostics.matchers.len(), 3);
+        assert_eq!(config.diagnostics.max_errors, 3);
+    }
+
+    #[test]
+    fn fails_when_diagnostics_preset_unknown() {
+        let yaml = "{hosts: {}, build: x, diagnostics: {presets: [javac]}}";
+        check_fail(yaml, "unknown \"diagnostics\" preset \"javac\"");
+    }
+
+    fn check_position(contents: &str, line: usize, column: usize) {
+        let config = parse_config(contents);
+        assert!(config.is_err(), "parse_config should have failed");
+        assert_eq!(config.unwrap_err().position(), Some((line, column)));
+    }
+
+    #[test]
+    fn fails_on_unknown_top_level_key() {
+        let yaml = "hosts: {}\nbulid: x";
+        check_fail(yaml, "unknown key \"bulid\", did you mean \"build\"?");
+        check_position(yaml, 2, 1);
+    }
+
+    #[test]
+    fn fails_on_unknown_host_key() {
+        let yaml = "
+hosts:
+    foo:
+        user: a
+        build_dri: b
+build: x";
+        check_fail(yaml,
+                   "unknown key \"build_dri\" in host \"foo\", did you mean \"build_dir\"?");
+        check_position(yaml, 5, 9);
+    }
+
+    #[test]
+    fn fails_on_unknown_nested_keys() {
+        let yaml = "{hosts: {}, build: x, gc: {max_histroy: 5}}";
+        check_fail(yaml,
+                   "unknown key \"max_histroy\" in \"gc\" configuration, did you mean \
+                    \"max_history\"?");
+        check_position(yaml, 1, 28);
+
+        let yaml = "{hosts: {}, build: x, diagnostics: {patterns: [{regex: a, level: b}]}}";
+        check_fail(yaml, "unknown key \"level\" in \"diagnostics\" pattern");
+        check_position(yaml, 1, 59);
+    }
+
+    #[test]
+    fn reports_first_unknown_key_in_file() {
+        let yaml = "hosts: {}\nzzz: 1\nbuild: x\naaa: 2";
+        check_fail(yaml, "unknown key \"zzz\"");
+        check_position(yaml, 2, 1);
+    }
+
+    #[test]
+    fn places_value_errors() {
+        check_position("hosts:\n  foo:\n    user: a\n    build_dir: [1]\nbuild: x", 4, 5);
+        check_position("hosts: {}\nbuild: x\ngc:\n  auto: -1", 4, 3);
+    }
+
+    #[test]
+    fn places_scan_errors() {
+        check_position("hosts: {}\nbuild: [x", 3, 1);
+    }
+
+    #[test]
+    fn renders_snippet() {
+        let error = parse_config("hosts: {}\nbulid: x").unwrap_err();
+        assert_eq!(error.render(),
+                   "unknown key \"bulid\", did you mean \"build\"?
+ --> bran.yml:2:1
+  |
+2 | bulid: x
+  | ^^^^^");
+    }
+
+    #[test]
+    fn renders_without_position() {
+        let error = parse_config("build: x").unwrap_err();
+        assert_eq!(error.render(), "missing \"hosts\" configuration");
+    }
+
+    #[test]
+    fn ignores_x_prefixed_keys() {
+        let yaml = "{hosts: {foo: {user: a, build_dir: b, x-os: linux}}, build: x, x-team: core}";
+        parse_config(yaml).expect("should parse successfully");
+    }
+
+    #[test]
+    fn suggests_close_keys_only() {
+        assert_eq!(super::suggest("bulid", &super::TOP_LEVEL_KEYS), Some("build"));
+        assert_eq!(super::suggest("hots", &super::TOP_LEVEL_KEYS), Some("hosts"));
+        assert_eq!(super::suggest("compiler", &super::TOP_LEVEL_KEYS), None);
+        assert_eq!(super::suggest("host", &super::DEFAULTS_KEYS), None);
+    }
+
+    #[test]
+    fn resolves_config_to_value() {
+        let yaml = "
+            hosts:
+                win:
+                    user: a
+                    build_dir: b
+            build: x
+            artifacts: out/*.exe
+            gc:
+                auto: 0";
+
+        let config = parse_config(yaml).expect("should parse successfully");
+        let resolved = config.to_value();
+
+        assert_eq!(resolved["hosts"]["win"]["user"].as_str(), Some("a"));
+        assert_eq!(resolved["hosts"]["win"]["artifacts"][0].as_str(), Some("out/*.exe"));
+        assert_eq!(resolved["build"][0].as_str(), Some("x"));
+        assert_eq!(resolved["gc"]["max_history"].as_i64(), Some(50));
+        assert_eq!(resolved["gc"]["auto"].as_i64(), Some(0));
+        assert_eq!(resolved["shadow_dir"].as_str(), Some(".bran"));
+    }
+
+    fn schema_keys(schema: &super::Value) -> Vec<String> {
+        let mut keys: Vec<String> = schema["properties"]
+                                        .as_table()
+                                        .expect("should have properties")
+                                        .keys()
+                                        .cloned()
+                                        .collect();
+        keys.sort();
+        keys
+    }
+
+    fn known_keys(known: &[&str]) -> Vec<String> {
+        let mut keys: Vec<String> = known.iter().map(|key| key.to_string()).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn schema_has_the_keys_the_parser_knows() {
+        let schema = super::schema();
+        let diagnostics = &schema["properties"]["diagnostics"];
+
+        assert_eq!(schema_keys(&schema), known_keys(&super::TOP_LEVEL_KEYS));
+        assert_eq!(schema_keys(&schema["properties"]["hosts"]["additionalProperties"]),
+                   known_keys(&super::HOST_KEYS));
+        assert_eq!(schema_keys(&schema["properties"]["defaults"]),
+                   known_keys(&super::DEFAULTS_KEYS));
+        assert_eq!(schema_keys(&schema["properties"]["gc"]), known_keys(&super::GC_KEYS));
+        assert_eq!(schema_keys(diagnostics), known_keys(&super::DIAGNOSTICS_KEYS));
+        assert_eq!(schema_keys(&diagnostics["properties"]["patterns"]["items"]),
+                   known_keys(&super::PATTERN_KEYS));
+    }
+
+    #[test]
+    fn schema_names_the_groups_the_matcher_reads() {
+        let schema = super::schema();
+        let regex = &schema["properties"]["diagnostics"]["properties"]["patterns"]["items"]
+                           ["properties"]["regex"];
+        let description = regex["description"].as_str().expect("should describe the regex");
+
+        for group in &GROUPS {
+            assert!(description.contains(&format!("\"{}\"", group)),
+                    "{} should mention \"{}\"",
+                    description,
+                    group);
+        }
+    }
+
+    #[test]
+    fn schema_allows_the_values_the_parser_does() {
+        let schema = super::schema();
+        let diagnostics = &schema["properties"]["diagnostics"]["properties"];
+
+        let presets = &diagnostics["presets"]["oneOf"][0]["enum"];
+        for preset in presets.as_vec().expect("should list presets") {
+            assert!(super::Matcher::preset(preset.as_str().unwrap()).is_some());
+        }
+
+        let severities = &diagnostics["patterns"]["items"]["properties"]["severity"]["enum"];
+        for severity in severities.as_vec().expect("should list severities") {
+            assert!(super::Severity::parse(severity.as_str().unwrap()).is_some());
+        }
+
+        assert_eq!(schema["patternProperties"]["^x-"], super::Value::Table(Default::default()));
+        assert_eq!(schema["additionalProperties"], super::Value::Boolean(false));
+    }
+
     #[test]
     fn git_ssh_command_with_identity_file() {
         let host = super::Host {
@@ -279,7 +2274,10 @@ mod tests {
             user: "user".into(),
             build_dir: "build_dir".into(),
             host: None,
+            port: None,
             identity_file: Some("id_rsa".into()),
+            artifacts: Vec::new(),
+            build: Vec::new(),
         };
 
         assert_eq!(host.git_ssh_command(), Some("ssh -i \"id_rsa\"".into()));
@@ -292,7 +2290,10 @@ mod tests {
             user: "user".into(),
             build_dir: "build_dir".into(),
             host: None,
+            port: None,
             identity_file: None,
+            artifacts: Vec::new(),
+            build: Vec::new(),
         };
 
         assert_eq!(host.git_ssh_command(), None);
@@ -305,12 +2306,33 @@ mod tests {
             user: "hodor".into(),
             build_dir: "winterfell".into(),
             host: None,
+            port: None,
             identity_file: None,
+            artifacts: Vec::new(),
+            build: Vec::new(),
         };
 
         assert_eq!(host.git_ssh_url(), "hodor@westeros:winterfell");
     }
 
+    #[test]
+    fn git_ssh_url_with_port() {
+        let host = super::Host {
+            name: "westeros".into(),
+            user: "hodor".into(),
+            build_dir: "/winterfell".into(),
+            host: None,
+            port: Some(2222),
+            identity_file: None,
+            artifacts: Vec::new(),
+            build: Vec::new(),
+        };
+
+        assert_eq!(host.git_ssh_url(), "ssh://hodor@westeros:2222/winterfell");
+        assert_eq!(host.ssh_command("true")[2..4], ["-p", "2222"]);
+        assert_eq!(host.scp_command("a", "b")[3..5], ["-P", "2222"]);
+    }
+
     #[test]
     fn ssh_command() {
         let host = super::Host {
@@ -318,7 +2340,10 @@ mod tests {
             user: "hodor".into(),
             build_dir: "winterfell".into(),
             host: Some("the-wall".into()),
+            port: None,
             identity_file: Some("id_rsa".into()),
+            artifacts: Vec::new(),
+            build: Vec::new(),
         };
 
         assert_eq!(host.ssh_command("echo hello"),
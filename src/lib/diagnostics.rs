@@ -0,0 +1,291 @@
+use regex;
+use regex::Regex;
+use log::strip_ansi;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// Everything Severity::parse accepts.
+pub const SEVERITIES: [&'static str; 3] = ["error", "fatal error", "warning"];
+
+impl Severity {
+    pub fn parse(name: &str) -> Option<Severity> {
+        match name {
+            "error" | "fatal error" => Some(Severity::Error),
+            "warning" => Some(Severity::Warning),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl Diagnostic {
+    pub fn location(&self) -> Option<String> {
+        match (&self.file, self.line, self.column) {
+            (&Some(ref file), Some(line), Some(column)) => {
+                Some(format!("{}:{}:{}", file, line, column))
+            }
+            (&Some(ref file), Some(line), None) => Some(format!("{}:{}", file, line)),
+            (&Some(ref file), None, _) => Some(file.clone()),
+            _ => None,
+        }
+    }
+}
+
+// A pattern with optional "severity", "message", "file", "line" and "column"
+// groups. Some compilers (rustc) print the location on a later line, which
+// the "location" pattern picks up for the preceding diagnostic.
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    pattern: Regex,
+    location: Option<Regex>,
+    ignore: Option<Regex>,
+    severity: Option<Severity>,
+}
+
+// The groups Matcher reads, which the schema describes.
+pub const GROUPS: [&'static str; 5] = ["severity", "message", "file", "line", "column"];
+
+pub const PRESETS: [&'static str; 3] = ["rustc", "gcc", "msvc"];
+
+const RUSTC_PATTERN: &'static str = r"^(?P<severity>error|warning)(?:\[\w+\])?: (?P<message>.+)$";
+const RUSTC_LOCATION: &'static str = r"^\s*--> (?P<file>.+?):(?P<line>\d+):(?P<column>\d+)";
+const RUSTC_IGNORE: &'static str = concat!(r"^(?:warning: .* generated \d+ warnings?",
+                                           r"|error: aborting due to",
+                                           r"|error: could not compile",
+                                           r"|warning: build failed)");
+
+const GCC_PATTERN: &'static str = concat!(r"^(?P<file>[^:\s][^:]*):(?P<line>\d+):",
+                                          r"(?:(?P<column>\d+):)? ",
+                                          r"(?P<severity>fatal error|error|warning): ",
+                                          r"(?P<message>.+)$");
+
+const MSVC_PATTERN: &'static str = concat!(r"^\s*(?P<file>[^(]+)",
+                                           r"\((?P<line>\d+)(?:,(?P<column>\d+))?\)\s*: ",
+                                           r"(?:fatal )?(?P<severity>error|warning) \w+: ",
+                                           r"(?P<message>.+)$");
+
+impl Matcher {
+    pub fn new(pattern: &str, severity: Option<Severity>) -> Result<Matcher, regex::Error> {
+        Ok(Matcher {
+            pattern: try!(Regex::new(pattern)),
+            location: None,
+            ignore: None,
+            severity: severity,
+        })
+    }
+
+    fn with(pattern: &str, location: Option<&str>, ignore: Option<&str>) -> Matcher {
+        Matcher {
+            pattern: Regex::new(pattern).unwrap(),
+            location: location.map(|location| Regex::new(location).unwrap()),
+            ignore: ignore.map(|ignore| Regex::new(ignore).unwrap()),
+            severity: None,
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Matcher> {
+        match name {
+            "rustc" => Some(Matcher::with(RUSTC_PATTERN, Some(RUSTC_LOCATION), Some(RUSTC_IGNORE))),
+            "gcc" => Some(Matcher::with(GCC_PATTERN, None, None)),
+            "msvc" => Some(Matcher::with(MSVC_PATTERN, None, None)),
+            _ => None,
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        self.pattern.as_str()
+    }
+
+    pub fn severity(&self) -> Option<Severity> {
+        self.severity
+    }
+
+    fn parse(&self, line: &str) -> Option<Diagnostic> {
+        if self.ignore.as_ref().map_or(false, |ignore| ignore.is_match(line)) {
+            return None;
+        }
+
+        let captures = match self.pattern.captures(line) {
+            Some(captures) => captures,
+            None => return None,
+        };
+
+        let severity = match captures.name("severity").and_then(Severity::parse) {
+            Some(severity) => severity,
+            None => self.severity.unwrap_or(Severity::Error),
+        };
+
+        Some(Diagnostic {
+            severity: severity,
+            message: captures.name("message").unwrap_or(line).trim().into(),
+            file: captures.name("file").map(|file| file.trim().into()),
+            line: captures.name("line").and_then(|line| line.parse().ok()),
+            column: captures.name("column").and_then(|column| column.parse().ok()),
+        })
+    }
+}
+
+// The errors and warnings found in one host's output.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics {
+    pub errors: Vec<Diagnostic>,
+    pub error_count: usize,
+    pub warning_count: usize,
+    max_errors: usize,
+    awaiting_location: Option<usize>,
+}
+
+impl Diagnostics {
+    pub fn new(max_errors: usize) -> Self {
+        Diagnostics { max_errors: max_errors, ..Diagnostics::default() }
+    }
+
+    pub fn scan(&mut self, matchers: &[Matcher], line: &str) {
+        let line = strip_ansi(line);
+
+        if let Some(index) = self.awaiting_location.take() {
+            if let Some(ref location) = matchers[index].location {
+                if let Some(captures) = location.captures(&line) {
+                    if let Some(last) = self.errors.last_mut() {
+                        last.file = captures.name("file").map(String::from);
+                        last.line = captures.name("line").and_then(|line| line.parse().ok());
+                        last.column = captures.name("column")
+                                              .and_then(|column| column.parse().ok());
+                    }
+                    return;
+                }
+
+                // The location usually follows directly, but rustc may
+                // print a blank line in between.
+                if line.trim().is_empty() {
+                    self.awaiting_location = Some(index);
+                    return;
+                }
+            }
+        }
+
+        for (index, matcher) in matchers.iter().enumerate() {
+            if let Some(diagnostic) = matcher.parse(&line) {
+                match diagnostic.severity {
+                    Severity::Warning => self.warning_count += 1,
+                    Severity::Error => {
+                        self.error_count += 1;
+
+                        if self.errors.len() < self.max_errors {
+                            if diagnostic.file.is_none() && matcher.location.is_some() {
+                                self.awaiting_location = Some(index);
+                            }
+
+                            self.errors.push(diagnostic);
+                        }
+                    }
+                }
+
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostics, Matcher, Severity, GROUPS};
+
+    fn scan(preset: &str, lines: &[&str]) -> Diagnostics {
+        let matchers = vec![Matcher::preset(preset).unwrap()];
+        let mut diagnostics = Diagnostics::new(10);
+
+        for line in lines {
+            diagnostics.scan(&matchers, line);
+        }
+
+        diagnostics
+    }
+
+    #[test]
+    fn parses_rustc() {
+        let diagnostics = scan("rustc",
+                               &["warning: unused variable: `x`",
+                                 "  --> src/main.rs:3:9",
+                                 "error[E0308]: mismatched types",
+                                 "  --> src/lib.rs:10:5",
+                                 "warning: `bran` (bin \"bran\") generated 1 warning",
+                                 "error: aborting due to previous error"]);
+
+        assert_eq!(diagnostics.warning_count, 1);
+        assert_eq!(diagnostics.error_count, 1);
+        assert_eq!(diagnostics.errors[0].message, "mismatched types");
+        assert_eq!(diagnostics.errors[0].location(), Some("src/lib.rs:10:5".into()));
+    }
+
+    #[test]
+    fn parses_gcc() {
+        let diagnostics = scan("gcc",
+                               &["main.c:4:10: fatal error: foo.h: No such file or directory",
+                                 "main.c:7:3: warning: unused variable 'x'"]);
+
+        assert_eq!(diagnostics.error_count, 1);
+        assert_eq!(diagnostics.warning_count, 1);
+        assert_eq!(diagnostics.errors[0].severity, Severity::Error);
+        assert_eq!(diagnostics.errors[0].location(), Some("main.c:4:10".into()));
+    }
+
+    #[test]
+    fn parses_msvc() {
+        let diagnostics = scan("msvc",
+                               &["c:\\src\\main.cpp(12): error C2065: 'x': undeclared identifier"]);
+
+        assert_eq!(diagnostics.error_count, 1);
+        assert_eq!(diagnostics.errors[0].location(), Some("c:\\src\\main.cpp:12".into()));
+        assert_eq!(diagnostics.errors[0].message, "'x': undeclared identifier");
+    }
+
+    #[test]
+    fn reads_every_group() {
+        let pattern = format!(r"^(?P<{}>\w+) (?P<{}>\w+) (?P<{}>\S+) (?P<{}>\d+) (?P<{}>\d+)$",
+                              GROUPS[0],
+                              GROUPS[1],
+                              GROUPS[2],
+                              GROUPS[3],
+                              GROUPS[4]);
+        let matchers = vec![Matcher::new(&pattern, None).unwrap()];
+        let mut diagnostics = Diagnostics::new(1);
+
+        diagnostics.scan(&matchers, "error oops main.c 4 10");
+
+        assert_eq!(diagnostics.errors[0].message, "oops");
+        assert_eq!(diagnostics.errors[0].location(), Some("main.c:4:10".into()));
+    }
+
+    #[test]
+    fn keeps_first_errors() {
+        let matchers = vec![Matcher::new(r"^FAIL (?P<message>.*)$", None).unwrap()];
+        let mut diagnostics = Diagnostics::new(1);
+
+        diagnostics.scan(&matchers, "FAIL one");
+        diagnostics.scan(&matchers, "FAIL two");
+
+        assert_eq!(diagnostics.error_count, 2);
+        assert_eq!(diagnostics.errors.len(), 1);
+        assert_eq!(diagnostics.errors[0].message, "one");
+    }
+}
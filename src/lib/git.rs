@@ -1,79 +1,526 @@
+use std::env;
 use std::io;
 use std::io::{Read, Write};
+use std::fs;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 use time;
 
+use artifacts::ARTIFACTS_DIR;
 use log::{Log, Output};
-use cmd::run;
-use config::Host;
+use cmd::{output, run};
+use config::{Gc, Host, LOCAL_CONFIG};
 
-fn git(log: &Log, args: &[&str], git_ssh_command: Option<String>) -> Result<(), io::Error> {
-    let mut args_with_tree = Vec::new();
-    args_with_tree.push("--git-dir=.bran");
-    args_with_tree.push("--work-tree=.");
-    args_with_tree.extend_from_slice(args);
-
-    let mut command = Command::new("git");
-    command.args(&args_with_tree);
-
-    if let Some(git_ssh_command) = git_ssh_command {
-        command.env("GIT_SSH_COMMAND", git_ssh_command);
-    }
-
-    log.cmd(&format!("git {}", args_with_tree.join(" ")));
-    run(command, &log)
-}
+const GITLINK_MODE: &'static str = "160000";
 
 fn write_file(path: &Path, content: &str, log: &Log) -> Result<(), io::Error> {
-    log.cmd(&format!("echo \"{}\" > {}", content, path.to_str().unwrap()));
+    log.plumbing(&format!("echo \"{}\" > {}", content, path.to_str().unwrap()));
     let mut file = try!(File::create(path));
     try!(file.write(content.as_bytes()));
     Ok(())
 }
 
-pub fn init(output: &Output) -> Result<(), io::Error> {
-    let log = Log::new("local", output);
+// Size of the object store in KiB, as reported by "git count-objects".
+pub fn parse_object_size(count_objects: &str) -> u64 {
+    count_objects.lines()
+                 .filter_map(|line| {
+                     let mut parts = line.splitn(2, ':');
+                     match (parts.next(), parts.next()) {
+                         (Some("size"), Some(value)) |
+                         (Some("size-pack"), Some(value)) |
+                         (Some("size-garbage"), Some(value)) => value.trim().parse().ok(),
+                         _ => None,
+                     }
+                 })
+                 .fold(0, |total, size: u64| total + size)
+}
 
-    try!(git(&log, &["init"], None));
+// The pattern excluding a shadow directory inside the work tree, such as
+// "/build/.bran" for "./build/x/../.bran". Absolute paths and paths that
+// leave the work tree are not matched by patterns, so they need none.
+fn exclude_pattern(git_dir: &Path) -> Option<String> {
+    let mut parts = Vec::new();
 
-    try!(write_file(&Path::new(".bran").join("info").join("exclude"),
-                    ".bran",
-                    &log));
+    for component in git_dir.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if parts.pop().is_none() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
 
-    try!(write_file(&Path::new(".bran").join("info").join("attributes"),
-                    "* -filter -diff -merge -text",
-                    &log));
+    if parts.is_empty() {
+        None
+    } else {
+        Some(format!("/{}", parts.join("/")))
+    }
+}
 
-    try!(git(&log, &["config", "user.name", "Brandon Stark"], None));
-    try!(git(&log, &["config", "user.email", "bran.stark@example.com"], None));
-    try!(git(&log, &["config", "core.autocrlf", "false"], None));
-    try!(git(&log, &["config", "core.ignorecase", "false"], None));
-    try!(git(&log, &["config", "commit.gpgsign", "false"], None));
-    Ok(())
+// The branch checked out in the project's own repository, if it has one.
+// This is read before there is anywhere to log to, so it isn't logged.
+pub fn current_branch(work_tree: &Path) -> Option<String> {
+    let result = Command::new("git")
+                     .current_dir(work_tree)
+                     .args(&["rev-parse", "--abbrev-ref", "HEAD"])
+                     .output();
+
+    match result {
+        Ok(ref result) if result.status.success() => {
+            let branch = String::from_utf8_lossy(&result.stdout).trim().to_string();
+
+            if branch.is_empty() || branch == "HEAD" {
+                None
+            } else {
+                Some(branch)
+            }
+        }
+        _ => None,
+    }
 }
 
-fn head() -> Result<String, io::Error> {
-    let mut file = try!(File::open(Path::new(".bran").join("refs").join("heads").join("master")));
-    let mut contents = String::new();
-    try!(file.read_to_string(&mut contents));
-    Ok(contents.trim().into())
+#[derive(Clone)]
+pub struct Repo {
+    git_dir: PathBuf,
+    log: Log,
+    output: Output,
 }
 
-pub fn commit(output: &Output) -> Result<String, io::Error> {
-    let log = Log::new("local", output);
-    let msg = format!("{}", time::now().rfc822z());
+impl Repo {
+    pub fn new(git_dir: &Path, output: &Output) -> Self {
+        Repo {
+            git_dir: git_dir.to_path_buf(),
+            log: Log::new("local", output),
+            output: output.clone(),
+        }
+    }
+
+    fn command(&self, work_tree: &Path, index: Option<&Path>, args: &[&str]) -> Command {
+        self.command_with_log(&self.log, work_tree, index, args)
+    }
+
+    fn command_with_log(&self,
+                        log: &Log,
+                        work_tree: &Path,
+                        index: Option<&Path>,
+                        args: &[&str])
+                        -> Command {
+        let git_dir_arg = format!("--git-dir={}", self.git_dir.display());
+        let work_tree_arg = format!("--work-tree={}", work_tree.display());
+
+        let mut args_with_tree = Vec::new();
+        args_with_tree.push(&git_dir_arg[..]);
+        args_with_tree.push(&work_tree_arg[..]);
+        args_with_tree.extend_from_slice(args);
+
+        let mut command = Command::new("git");
+        command.args(&args_with_tree);
+
+        if let Some(index) = index {
+            command.env("GIT_INDEX_FILE", index);
+            log.plumbing(&format!("GIT_INDEX_FILE={} git {}",
+                                  index.display(),
+                                  args_with_tree.join(" ")));
+        } else {
+            log.plumbing(&format!("git {}", args_with_tree.join(" ")));
+        }
+
+        command
+    }
+
+    fn git(&self, args: &[&str], git_ssh_command: Option<String>) -> Result<(), io::Error> {
+        let mut command = self.command(Path::new("."), None, args);
+
+        if let Some(git_ssh_command) = git_ssh_command {
+            command.env("GIT_SSH_COMMAND", git_ssh_command);
+        }
+
+        run(command, &self.log)
+    }
+
+    fn git_index(&self,
+                 work_tree: &Path,
+                 index: Option<&Path>,
+                 args: &[&str])
+                 -> Result<(), io::Error> {
+        run(self.command(work_tree, index, args), &self.log)
+    }
+
+    fn git_index_output(&self,
+                        work_tree: &Path,
+                        index: Option<&Path>,
+                        args: &[&str])
+                        -> Result<String, io::Error> {
+        output(self.command(work_tree, index, args), &self.log)
+    }
+
+    fn git_output(&self, args: &[&str]) -> Result<String, io::Error> {
+        self.git_index_output(Path::new("."), None, args).map(|out| out.trim().to_string())
+    }
+
+    // Shadow repositories made by older versions lack some of these
+    // entries, so the file is brought up to date before every use rather
+    // than only written by init.
+    fn update_exclude(&self) -> Result<(), io::Error> {
+        let path = self.git_dir.join("info").join("exclude");
+        let mut exclude = format!("/{}\n/{}.*", ARTIFACTS_DIR, LOCAL_CONFIG);
+
+        if let Some(pattern) = exclude_pattern(&self.git_dir) {
+            exclude.push_str(&format!("\n{}", pattern));
+        }
+
+        let mut current = String::new();
+        if File::open(&path).and_then(|mut file| file.read_to_string(&mut current)).is_ok() &&
+           current == exclude {
+            return Ok(());
+        }
+
+        write_file(&path, &exclude, &self.log)
+    }
+
+    pub fn init(&self) -> Result<(), io::Error> {
+        try!(self.git(&["init"], None));
+        try!(self.update_exclude());
+
+        try!(write_file(&self.git_dir.join("info").join("attributes"),
+                        "* -filter -diff -merge -text",
+                        &self.log));
+
+        try!(self.git(&["config", "user.name", "Brandon Stark"], None));
+        try!(self.git(&["config", "user.email", "bran.stark@example.com"], None));
+        try!(self.git(&["config", "core.autocrlf", "false"], None));
+        try!(self.git(&["config", "core.ignorecase", "false"], None));
+        try!(self.git(&["config", "commit.gpgsign", "false"], None));
+        Ok(())
+    }
+
+    fn head(&self) -> Result<String, io::Error> {
+        self.git_output(&["rev-parse", "--verify", "HEAD"])
+    }
+
+    fn gitlinks(&self, work_tree: &Path, index: Option<&Path>) -> Result<Vec<String>, io::Error> {
+        let staged = try!(self.git_index_output(work_tree, index, &["ls-files", "--stage", "-z"]));
+        let mut links = Vec::new();
+
+        // Each entry looks like "<mode> <hash> <stage>\t<path>".
+        for entry in staged.split('\0') {
+            let mut parts = entry.splitn(2, '\t');
+            let info = parts.next().unwrap_or("");
+            let path = parts.next().unwrap_or("");
+
+            if info.starts_with(GITLINK_MODE) && !path.is_empty() {
+                links.push(path.to_string());
+            }
+        }
+
+        Ok(links)
+    }
+
+    // git reads a relative GIT_INDEX_FILE from inside the work tree, which
+    // for nested repositories isn't the current directory.
+    fn index_path(&self, name: &str) -> Result<PathBuf, io::Error> {
+        Ok(try!(env::current_dir()).join(&self.git_dir).join(name))
+    }
+
+    fn nested_index(&self, depth: usize) -> Result<PathBuf, io::Error> {
+        self.index_path(&format!("index.nested.{}", depth))
+    }
+
+    // Nested repositories (submodules or vendored checkouts) are staged as
+    // gitlinks, which would leave them empty on the remote. Replace each
+    // gitlink with a tree built from the nested repository's working tree.
+    fn add_nested(&self,
+                  work_tree: &Path,
+                  index: Option<&Path>,
+                  depth: usize)
+                  -> Result<(), io::Error> {
+        for link in try!(self.gitlinks(work_tree, index)) {
+            let nested_tree = work_tree.join(&link);
+            let nested_index = try!(self.nested_index(depth));
+
+            fs::remove_file(&nested_index).ok();
+            try!(self.git_index(&nested_tree, Some(&nested_index), &["add", "-A", "."]));
+            try!(self.add_nested(&nested_tree, Some(&nested_index), depth + 1));
+
+            let tree = try!(self.git_index_output(&nested_tree,
+                                                  Some(&nested_index),
+                                                  &["write-tree"]));
+            fs::remove_file(&nested_index).ok();
+
+            let prefix = format!("--prefix={}/", link);
+            try!(self.git_index(work_tree,
+                                index,
+                                &["rm", "--cached", "-q", "--ignore-unmatch", &link]));
+            try!(self.git_index(work_tree, index, &["read-tree", &prefix, tree.trim()]));
+        }
+
+        Ok(())
+    }
+
+    fn history_len(&self) -> Result<u64, io::Error> {
+        let count = try!(self.git_output(&["rev-list", "--count", "HEAD"]));
+        count.parse()
+             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid commit count"))
+    }
+
+    // Replace the whole history with a single parentless commit of the
+    // current tree, so that old snapshots become unreachable and can be
+    // collected.
+    fn squash(&self, msg: &str) -> Result<(), io::Error> {
+        let tree = try!(self.git_output(&["write-tree"]));
+        let commit = try!(self.git_output(&["commit-tree", &tree, "-m", msg]));
+
+        try!(self.git(&["update-ref", "refs/heads/master", &commit], None));
+        self.git(&["reflog", "expire", "--expire=now", "--all"], None)
+    }
+
+    pub fn commit(&self, gc: &Gc) -> Result<String, io::Error> {
+        let msg = format!("{}", time::now().rfc822z());
+
+        try!(self.update_exclude());
+        self.git(&["add", "-A", "."], None).ok();
+        try!(self.add_nested(Path::new("."), None, 0));
+        self.git(&["commit", "-m", &msg], None).ok();
+
+        if gc.max_history > 0 && self.history_len().unwrap_or(0) > gc.max_history {
+            try!(self.squash(&msg));
+        }
+
+        self.head()
+    }
+
+    // Run after the hosts are done with the commit, so that it doesn't
+    // delay them.
+    pub fn gc_auto(&self, gc: &Gc) -> Result<(), io::Error> {
+        if gc.auto == 0 {
+            return Ok(());
+        }
+
+        let auto = format!("gc.auto={}", gc.auto);
+        self.git(&["-c", &auto, "gc", "--auto", "--quiet"], None)
+    }
+
+    pub fn gc(&self) -> Result<(u64, u64), io::Error> {
+        let before = parse_object_size(&try!(self.git_output(&["count-objects", "-v"])));
+        try!(self.git(&["reflog", "expire", "--expire=now", "--all"], None));
+        try!(self.git(&["gc", "--prune=now", "--quiet"], None));
+        let after = parse_object_size(&try!(self.git_output(&["count-objects", "-v"])));
+
+        Ok((before, after))
+    }
 
-    git(&log, &["add", "-A", "."], None).ok();
-    git(&log, &["commit", "-m", &msg], None).ok();
-    head()
+    // Every path that would be synced, nested repositories included. The
+    // commands aren't logged since watch runs this whenever files change.
+    pub fn files(&self) -> Result<Vec<String>, io::Error> {
+        try!(self.update_exclude());
+        self.files_under(Path::new("."), "")
+    }
+
+    // Against an index that doesn't exist every file is untracked, and git
+    // lists a nested repository as its directory, ending in "/".
+    fn files_under(&self, root: &Path, prefix: &str) -> Result<Vec<String>, io::Error> {
+        let mut command = Command::new("git");
+        command.arg(format!("--git-dir={}", self.git_dir.display()))
+               .arg(format!("--work-tree={}", root.join(prefix).display()))
+               .args(&["ls-files", "-z", "--others", "--exclude-standard"])
+               .env("GIT_INDEX_FILE", try!(self.index_path("index.none")));
+
+        let listing = try!(output(command, &self.log));
+        let mut files = Vec::new();
+
+        for path in listing.split('\0').filter(|path| !path.is_empty()) {
+            let path = format!("{}{}", prefix, path);
+
+            if path.ends_with('/') {
+                files.extend(try!(self.files_under(root, &path)));
+            } else {
+                files.push(path);
+            }
+        }
+
+        Ok(files)
+    }
+
+    // Pushes run concurrently, so log them under the host they are for.
+    pub fn push(&self, host: &Host) -> Result<(), io::Error> {
+        let log = Log::new(&host.name, &self.output);
+        let url = host.git_ssh_url();
+        let mut command = self.command_with_log(&log,
+                                                Path::new("."),
+                                                None,
+                                                &["push", "-f", &url, "master:bran"]);
+
+        if let Some(git_ssh_command) = host.git_ssh_command() {
+            command.env("GIT_SSH_COMMAND", git_ssh_command);
+        }
+
+        run(command, &log)
+    }
 }
 
-pub fn push(host: &Host, output: &Output) -> Result<(), io::Error> {
-    let log = Log::new("local", output);
-    let url = host.git_ssh_url();
-    let env = host.git_ssh_command();
-    try!(git(&log, &["push", "-f", &url, "master:bran"], env));
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use libc;
+    use log::Output;
+    use super::{exclude_pattern, Repo};
+
+    // Tests run concurrently, so each gets its own directory.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bran-{}-{}", name, unsafe { libc::getpid() }));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+                         .current_dir(dir)
+                         .args(&["-c", "user.name=Hodor", "-c", "user.email=hodor@example.com"])
+                         .args(args)
+                         .output()
+                         .unwrap()
+                         .status;
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn excludes_shadow_dirs_inside_the_work_tree() {
+        assert_eq!(exclude_pattern(Path::new(".bran")), Some("/.bran".into()));
+        assert_eq!(exclude_pattern(Path::new("./.bran")), Some("/.bran".into()));
+        assert_eq!(exclude_pattern(Path::new("sub/../.bran")), Some("/.bran".into()));
+        assert_eq!(exclude_pattern(Path::new("./build//x/../.bran/")),
+                   Some("/build/.bran".into()));
+        assert_eq!(exclude_pattern(Path::new("../shadow")), None);
+        assert_eq!(exclude_pattern(Path::new("/tmp/shadow")), None);
+    }
+
+    #[test]
+    fn updates_the_exclude_file_of_older_shadow_repositories() {
+        let dir = scratch("exclude");
+        let work = dir.join("work");
+
+        write(&work.join("main.rs"), "");
+        write(&work.join("bran-artifacts/linux/bran"), "");
+        write(&work.join("bran.local.yml"), "");
+
+        let repo = Repo::new(&dir.join("shadow"), &Output::new());
+        repo.init().unwrap();
+        write(&dir.join("shadow/info/exclude"), "");
+
+        repo.update_exclude().unwrap();
+        assert_eq!(repo.files_under(&work, "").unwrap(), ["main.rs"]);
+    }
+
+    // Every entry of the tree the shadow repository would commit for the
+    // work tree, as "<mode> <path>".
+    fn shadow_tree(repo: &Repo, work_tree: &Path) -> Vec<String> {
+        repo.git_index(work_tree, None, &["add", "-A", "."]).unwrap();
+        repo.add_nested(work_tree, None, 0).unwrap();
+
+        let tree = repo.git_index_output(work_tree, None, &["write-tree"]).unwrap();
+        let listing = repo.git_index_output(work_tree, None, &["ls-tree", "-r", tree.trim()])
+                          .unwrap();
+
+        listing.lines()
+               .map(|line| {
+                   let mode = line.split(' ').next().unwrap();
+                   let path = line.splitn(2, '\t').nth(1).unwrap();
+                   format!("{} {}", mode, path)
+               })
+               .collect()
+    }
+
+    #[test]
+    fn syncs_nested_repositories_and_submodules() {
+        let dir = scratch("nested");
+        let work = dir.join("work");
+
+        write(&work.join("main.rs"), "fn main() {}");
+
+        // A vendored checkout, with a file it ignores and one it doesn't
+        // track yet.
+        write(&work.join("vendor/lib/lib.rs"), "pub fn lib() {}");
+        write(&work.join("vendor/lib/.gitignore"), "target/");
+        write(&work.join("vendor/lib/target/lib.rlib"), "");
+        git(&work.join("vendor/lib"), &["init", "-q"]);
+        git(&work.join("vendor/lib"), &["add", "lib.rs", ".gitignore"]);
+        git(&work.join("vendor/lib"), &["commit", "-q", "-m", "lib"]);
+        write(&work.join("vendor/lib/new.rs"), "");
+
+        // A submodule checkout, whose .git is a file pointing elsewhere, with
+        // another repository nested inside it. git only makes gitlinks of
+        // repositories that have a commit.
+        write(&work.join("sub/mod.rs"), "mod deep;");
+        write(&work.join("sub/deep/deep.rs"), "");
+        let modules = dir.join("modules").join("sub");
+        fs::create_dir_all(&dir.join("modules")).unwrap();
+        git(&work.join("sub/deep"), &["init", "-q"]);
+        git(&work.join("sub/deep"), &["add", "."]);
+        git(&work.join("sub/deep"), &["commit", "-q", "-m", "deep"]);
+        git(&work.join("sub"),
+            &["init", "-q", "--separate-git-dir", modules.to_str().unwrap()]);
+        git(&work.join("sub"), &["add", "mod.rs"]);
+        git(&work.join("sub"), &["commit", "-q", "-m", "sub"]);
+
+        let repo = Repo::new(&dir.join("shadow"), &Output::new());
+        repo.init().unwrap();
+
+        assert_eq!(shadow_tree(&repo, &work),
+                   ["100644 main.rs",
+                    "100644 sub/deep/deep.rs",
+                    "100644 sub/mod.rs",
+                    "100644 vendor/lib/.gitignore",
+                    "100644 vendor/lib/lib.rs",
+                    "100644 vendor/lib/new.rs"]);
+
+        // Watch looks at the same files.
+        let mut files = repo.files_under(&work, "").unwrap();
+        files.sort();
+        assert_eq!(files,
+                   ["main.rs",
+                    "sub/deep/deep.rs",
+                    "sub/mod.rs",
+                    "vendor/lib/.gitignore",
+                    "vendor/lib/lib.rs",
+                    "vendor/lib/new.rs"]);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn syncs_nested_repositories_with_a_relative_shadow_dir() {
+        let dir = scratch("nested-relative");
+        let work = dir.join("work");
+        write(&work.join("vendor/lib/lib.rs"), "");
+        git(&work.join("vendor/lib"), &["init", "-q"]);
+        git(&work.join("vendor/lib"), &["add", "."]);
+        git(&work.join("vendor/lib"), &["commit", "-q", "-m", "lib"]);
+
+        // Relative to the current directory, like the default ".bran".
+        let up: PathBuf = env::current_dir().unwrap().components().skip(1).map(|_| "..").collect();
+        let shadow = up.join(dir.join("shadow").strip_prefix("/").unwrap());
+
+        let repo = Repo::new(&shadow, &Output::new());
+        repo.init().unwrap();
+
+        assert_eq!(shadow_tree(&repo, &work), ["100644 vendor/lib/lib.rs"]);
+
+        fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -1,8 +1,10 @@
 use std::error::Error;
 use std::io;
 use std::process::Command;
-use config::Host;
+use cancel::Cancel;
+use config::{Gc, Host};
 use log::{Log, Output};
+use git;
 use cmd;
 
 pub struct Host {
@@ -48,28 +50,71 @@ impl Host {
     }
 }
 
+pub struct Remote {
+    host: Host,
+    log: Log,
+    cancel: Option<Cancel>,
+}
+
+// Remote commands started by a cancellable run record the pid of their
+// shell. sshd makes that shell a process group leader, so the whole group
+// can be killed when the run is cancelled.
+const PID_FILE: &'static str = ".git/bran.pid";
+
 impl Remote {
     pub fn new(host: &Host, output: &Output) -> Self {
         Remote {
             host: host.clone(),
             log: Log::new(&host.name, output),
+            cancel: None,
+        }
+    }
+
+    pub fn with_cancel(host: &Host, output: &Output, cancel: &Cancel) -> Self {
+        Remote {
+            host: host.clone(),
+            log: Log::new(&host.name, output),
+            cancel: Some(cancel.clone()),
         }
     }
 
     fn ssh(&self, cmd: &str) -> Result<(), io::Error> {
         let args = self.host.ssh_command(cmd);
+        self.log.trace(&format!("ssh {}", args.join(" ")));
 
         let mut command = Command::new("ssh");
         command.args(&args);
 
-        if let Err(err) = cmd::run(command, &self.log) {
-            self.log.error(err.description());
+        let result = match self.cancel {
+            Some(ref cancel) => cmd::run_cancellable(command, &self.log, cancel),
+            None => cmd::run(command, &self.log),
+        };
+
+        if let Err(err) = result {
+            if err.kind() == io::ErrorKind::Interrupted {
+                self.kill();
+            } else if !self.cancel.as_ref().map_or(false, |cancel| cancel.is_cancelled()) {
+                self.log.error(err.description());
+            }
+
             return Err(err);
         }
 
         Ok(())
     }
 
+    fn kill(&self) {
+        let kill = format!("cd \"{}\" && kill -TERM -- -$(cat {}) 2>/dev/null; rm -f {}",
+                           self.host.build_dir,
+                           PID_FILE,
+                           PID_FILE);
+        self.log.plumbing(&kill);
+
+        let mut command = Command::new("ssh");
+        command.args(&self.host.ssh_command(&kill));
+        cmd::run(command, &self.log).ok();
+    }
+
     pub fn init(&self) -> Result<(), io::Error> {
         let mkdir = format!("mkdir -p \"{}\"", self.host.build_dir);
         self.log.cmd(&mkdir);
@@ -80,108 +125,54 @@ impl Remote {
 
     pub fn run(&self, cmd: &str) -> Result<(), io::Error> {
         self.log.cmd(cmd);
-        self.ssh(&format!("cd \"{}\"; {}", self.host.build_dir, cmd))
+        self.exec(cmd)
     }
-}
-
-
-use std::error::Error;
-use std::thread;
-use std::thread::JoinHandle;
-use git;
-use log::{Log, Output};
-use remote::Remote;
-use config::{Config, Host};
-
-fn remote_build(host: Host,
-                hash: String,
-                cmds: Vec<String>,
-                output: Output)
-                -> Result<(), Box<Error>> {
-
-    try!(git::push(&host, &output));
 
-    let remote = Remote::new(&host, &output);
-    try!(remote.run(&format!("git reset {} --hard", hash)));
-
-    for cmd in &cmds {
-        try!(remote.run(cmd));
+    pub fn reset(&self, hash: &str) -> Result<(), io::Error> {
+        let reset = format!("git reset {} --hard", hash);
+        self.log.plumbing(&reset);
+        self.exec(&reset)
     }
 
-    Ok(())
-}
-
-fn run_remote_build(host: Host, hash: String, cmds: Vec<String>, output: Output) -> bool {
-    let remote_log = Log::new(&host.name, &output);
-
-    match remote_build(host, hash, cmds, output) {
-        Ok(_) => {
-            remote_log.success("Build succeeded");
-            true
-        }
-        Err(_) => {
-            remote_log.error("Build failed");
-            false
+    fn exec(&self, cmd: &str) -> Result<(), io::Error> {
+        if self.cancel.is_some() {
+            self.ssh(&format!("cd \"{}\"; echo $$ > {}; {}",
+                              self.host.build_dir,
+                              PID_FILE,
+                              cmd))
+        } else {
+            self.ssh(&format!("cd \"{}\"; {}", self.host.build_dir, cmd))
         }
     }
-}
-
-fn spawn_build(host: &Host,
-               hash: &str,
-               config: &Config,
-               output: &Output)
-               -> (String, JoinHandle<bool>) {
-
-    let thread_host = host.clone();
-    let thread_hash = hash.to_string();
-    let thread_cmds = config.build.clone();
-    let thread_output = output.clone();
 
-    let handle = thread::spawn(move || {
-        run_remote_build(thread_host, thread_hash, thread_cmds, thread_output)
-    });
+    pub fn output(&self, cmd: &str) -> Result<String, io::Error> {
+        self.log.plumbing(cmd);
 
-    (host.name.to_string(), handle)
-}
-
-fn join_all(threads: Vec<(String, JoinHandle<bool>)>) -> Vec<(String, bool)> {
-    threads.into_iter().map(|pair| (pair.0, pair.1.join().unwrap_or(false))).collect()
-}
+        let args = self.host.ssh_command(&format!("cd \"{}\"; {}", self.host.build_dir, cmd));
+        self.log.trace(&format!("ssh {}", args.join(" ")));
 
-fn print_summary(results: &[(String, bool)], output: &Output) {
-    println!("\n---------- Summary ----------\n");
-
-    for result in results {
-        let remote_log = Log::new(&result.0, output);
+        let mut command = Command::new("ssh");
+        command.args(&args);
 
-        if result.1 {
-            remote_log.success("Build succeeded");
-        } else {
-            remote_log.error("Build failed");
-        }
+        cmd::output(command, &self.log)
     }
-}
 
-fn all_succeeded(results: &[(String, bool)]) -> bool {
-    results.iter().all(|pair| pair.1)
-}
-
-pub fn build(config: Config) -> Result<i32, Box<Error>> {
-    let output = Output::new();
-    let hash = try!(git::commit(&output));
-
-    let results = join_all(config.hosts
-                                 .values()
-                                 .map(|host| spawn_build(host, &hash, &config, &output))
-                                 .collect());
+    pub fn gc_auto(&self, gc: &Gc) -> Result<(), io::Error> {
+        if gc.auto == 0 {
+            return Ok(());
+        }
 
-    if results.len() > 1 {
-        print_summary(&results, &output);
+        let gc = format!("git -c gc.auto={} gc --auto --quiet", gc.auto);
+        let cmd = format!("git reflog expire --expire=now --all && {}", gc);
+        self.log.plumbing(&cmd);
+        self.exec(&cmd)
     }
 
-    if all_succeeded(&results) {
-        Ok(0)
-    } else {
-        Ok(1)
+    pub fn gc(&self) -> Result<(u64, u64), io::Error> {
+        let before = git::parse_object_size(&try!(self.output("git count-objects -v")));
+        try!(self.run("git reflog expire --expire=now --all && git gc --prune=now --quiet"));
+        let after = git::parse_object_size(&try!(self.output("git count-objects -v")));
+
+        Ok((before, after))
     }
 }
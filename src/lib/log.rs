@@ -1,51 +1,610 @@
+#[cfg(test)]
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 use libc;
+use time;
+use config::DiagnosticsConfig;
+use diagnostics::Diagnostics;
 use ansi_term::{Color, Style};
 
+// Number of output lines kept per host to show under a failure in the
+// summary, unless --tail-lines says otherwise.
+pub const DEFAULT_TAIL_LINES: usize = 10;
+
 #[derive(Copy, Clone)]
 pub enum Stream {
     StdOut,
     StdErr,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    Interleaved,
+    Grouped,
+    Dashboard,
+    Jsonl,
+}
+
+impl Mode {
+    pub fn parse(name: &str) -> Option<Mode> {
+        match name {
+            "interleaved" => Some(Mode::Interleaved),
+            "grouped" => Some(Mode::Grouped),
+            "dashboard" => Some(Mode::Dashboard),
+            "jsonl" => Some(Mode::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    VeryVerbose,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Timestamps {
+    Wall,
+    Relative,
+}
+
+impl Timestamps {
+    pub fn parse(name: &str) -> Option<Timestamps> {
+        match name {
+            "wall" => Some(Timestamps::Wall),
+            "relative" => Some(Timestamps::Relative),
+            _ => None,
+        }
+    }
+}
+
+pub fn format_duration(duration: &Duration) -> String {
+    let secs = duration.as_secs();
+    let tenths = duration.subsec_nanos() / 100_000_000;
+
+    if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}.{}s", secs, tenths)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(name: &str) -> Option<ColorChoice> {
+        match name {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+}
+
+// An explicit --color wins; otherwise NO_COLOR turns color off and
+// CLICOLOR_FORCE turns it on even when not writing to a terminal.
+fn should_color(choice: ColorChoice,
+                is_tty: bool,
+                no_color: Option<String>,
+                clicolor_force: Option<String>)
+                -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            if no_color.map_or(false, |value| !value.is_empty()) {
+                false
+            } else if clicolor_force.map_or(false, |value| !value.is_empty() && value != "0") {
+                true
+            } else {
+                is_tty
+            }
+        }
+    }
+}
+
+// Colors for host headers, picked in order of first appearance. Red and
+// green are left out since they mark errors and successes.
+const HEADER_COLORS: [Color; 8] = [Color::Cyan,
+                                   Color::Yellow,
+                                   Color::Purple,
+                                   Color::Blue,
+                                   Color::Fixed(208),
+                                   Color::Fixed(73),
+                                   Color::Fixed(141),
+                                   Color::Fixed(108)];
+
+// Removes escape sequences: CSI ("ESC [ ... final"), OSC ("ESC ] ... BEL" or
+// "ESC ] ... ESC \\") and two character escapes.
+pub fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('[') => {
+                while let Some(c) = chars.next() {
+                    if c >= '\x40' && c <= '\x7e' {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+
+// Decodes a line of child output. Invalid UTF-8 is replaced rather than
+// rejected, and a line redrawn with carriage returns (a progress bar) is
+// collapsed to the last state it showed.
+pub fn decode_line(bytes: &[u8], keep_ansi: bool) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let text = text.trim_right_matches(|c| c == '\n' || c == '\r');
+
+    let last = text.split('\r')
+                   .filter(|part| !strip_ansi(part).trim().is_empty())
+                   .last()
+                   .unwrap_or("");
+
+    if keep_ansi {
+        last.into()
+    } else {
+        strip_ansi(last)
+    }
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() + 2);
+    result.push('"');
+
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c < ' ' => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+// One event of the jsonl output, as a single line JSON object. Values are
+// JSON already, see json_string.
+fn json_event(event: &str, time: &str, fields: &[(&str, String)]) -> String {
+    let mut parts = vec![format!("\"event\":{}", json_string(event)),
+                         format!("\"time\":{}", json_string(time))];
+
+    for &(name, ref value) in fields {
+        parts.push(format!("{}:{}", json_string(name), value));
+    }
+
+    format!("{{{}}}", parts.join(","))
+}
+
+fn stream_name(stream: Stream) -> &'static str {
+    match stream {
+        Stream::StdOut => "stdout",
+        Stream::StdErr => "stderr",
+    }
+}
+
+fn terminal_width() -> usize {
+    unsafe {
+        let mut size: libc::winsize = ::std::mem::zeroed();
+
+        if libc::ioctl(1, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            size.ws_col as usize
+        } else {
+            80
+        }
+    }
+}
+
+// Everything written, for tests to look at.
+#[cfg(test)]
+thread_local!(static WRITTEN: RefCell<Vec<String>> = RefCell::new(Vec::new()));
+
+#[cfg(test)]
+fn capture(formatted: &str) {
+    WRITTEN.with(|written| written.borrow_mut().push(formatted.into()));
+}
+
+#[cfg(not(test))]
+fn capture(_: &str) {}
+
+// A host that has started but not yet finished. In grouped and dashboard
+// modes its output is kept back: grouped mode prints it as one block when
+// the host finishes, the dashboard only if the host failed.
+struct Running {
+    header: String,
+    lines: Vec<(Stream, String)>,
+    step: String,
+    last_line: String,
+    started: Instant,
+    succeeded: Option<bool>,
+    finished: bool,
+}
+
+fn format_elapsed(started: &Instant) -> String {
+    let secs = started.elapsed().as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    line.chars().take(width.saturating_sub(1)).collect()
+}
+
+// Keeps the full, uncolored output of every host in its own file under a
+// directory per run, alongside the terminal output.
+pub struct LogFiles {
+    dir: PathBuf,
+    files: HashMap<String, File>,
+}
+
+impl LogFiles {
+    pub fn create(root: &Path, keep: usize) -> Result<Self, io::Error> {
+        let timestamp = format!("{}", time::now().strftime("%Y%m%d-%H%M%S").unwrap());
+        try!(fs::create_dir_all(root));
+
+        // Runs started within the same second get a counter after the
+        // timestamp, so that they don't share a directory.
+        let mut dir = root.join(&timestamp);
+        let mut count = 0;
+
+        loop {
+            match fs::create_dir(&dir) {
+                Ok(()) => break,
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    count += 1;
+                    dir = root.join(format!("{}-{}", timestamp, count));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        try!(prune_runs(root, keep));
+
+        Ok(LogFiles {
+            dir: dir,
+            files: HashMap::new(),
+        })
+    }
+
+    fn write(&mut self, header: &str, text: &str) {
+        if !self.files.contains_key(header) {
+            match File::create(self.dir.join(format!("{}.log", header))) {
+                Ok(file) => {
+                    self.files.insert(header.into(), file);
+                }
+                Err(_) => return,
+            }
+        }
+
+        if let Some(file) = self.files.get_mut(header) {
+            writeln!(file, "{}", text.trim_right()).ok();
+        }
+    }
+}
+
+// Whether a directory is named like the ones LogFiles creates, as
+// "YYYYmmdd-HHMMSS" with an optional "-N" counter.
+fn is_run_dir(name: &str) -> bool {
+    let digits = |part: &str, len: usize| {
+        part.len() == len && part.chars().all(|c| c.is_digit(10))
+    };
+
+    let parts: Vec<&str> = name.split('-').collect();
+
+    match parts.len() {
+        2 => digits(parts[0], 8) && digits(parts[1], 6),
+        3 => digits(parts[0], 8) && digits(parts[1], 6) && !parts[2].is_empty() &&
+             parts[2].chars().all(|c| c.is_digit(10)),
+        _ => false,
+    }
+}
+
+// The log directory can be anywhere (--log-dir), so only directories bran
+// created are removed. They are named by timestamp, so sorting them by name
+// puts the oldest first.
+fn prune_runs(root: &Path, keep: usize) -> Result<(), io::Error> {
+    let mut runs = Vec::new();
+
+    for entry in try!(fs::read_dir(root)) {
+        let entry = try!(entry);
+        let is_run = entry.file_name().to_str().map_or(false, is_run_dir);
+
+        if is_run && try!(entry.file_type()).is_dir() {
+            runs.push(entry.path());
+        }
+    }
+
+    runs.sort();
+
+    if runs.len() > keep {
+        let excess = runs.len() - keep;
+        for run in &runs[..excess] {
+            try!(fs::remove_dir_all(run));
+        }
+    }
+
+    Ok(())
+}
+
 struct RawLog {
     stdout_is_tty: bool,
     stderr_is_tty: bool,
+    stdout_color: bool,
+    stderr_color: bool,
+    header_colors: HashMap<String, Color>,
+    files: Option<LogFiles>,
+    mode: Mode,
+    running: Vec<Running>,
+    status_rows: usize,
+    timestamps: Option<Timestamps>,
+    run_started: Instant,
+    verbosity: Verbosity,
+    // The command each header is running, with its id if it was shown, so
+    // that its end is only shown along with it. Commands that were never
+    // announced, such as the queries watch polls with, have no entry.
+    cmds: HashMap<String, Option<u64>>,
+    cmd_count: u64,
+    diagnostics_config: DiagnosticsConfig,
+    diagnostics: HashMap<String, Diagnostics>,
+    // The output of the command each host ran last, so that the summary
+    // shows the command that failed.
+    tails: HashMap<String, VecDeque<String>>,
+    tail_lines: usize,
 }
 
 impl RawLog {
     fn new() -> Self {
-        unsafe {
-            RawLog {
-                stdout_is_tty: libc::isatty(1) != 0,
-                stderr_is_tty: libc::isatty(2) != 0,
-            }
+        let (stdout_is_tty, stderr_is_tty) = unsafe {
+            (libc::isatty(1) != 0, libc::isatty(2) != 0)
+        };
+
+        RawLog {
+            stdout_is_tty: stdout_is_tty,
+            stderr_is_tty: stderr_is_tty,
+            stdout_color: false,
+            stderr_color: false,
+            header_colors: HashMap::new(),
+            files: None,
+            mode: Mode::Interleaved,
+            running: Vec::new(),
+            status_rows: 0,
+            timestamps: None,
+            run_started: Instant::now(),
+            verbosity: Verbosity::Normal,
+            cmds: HashMap::new(),
+            cmd_count: 0,
+            diagnostics_config: DiagnosticsConfig::default(),
+            diagnostics: HashMap::new(),
+            tails: HashMap::new(),
+            tail_lines: DEFAULT_TAIL_LINES,
         }
     }
 
-    fn use_color(&self, stream: Stream) -> bool {
-        match stream {
-            Stream::StdOut => self.stdout_is_tty,
-            Stream::StdErr => self.stderr_is_tty,
+    fn running_mut(&mut self, header: &str) -> Option<&mut Running> {
+        self.running.iter_mut().find(|running| running.header == header)
+    }
+
+    fn event(&self, event: &str, fields: &[(&str, String)]) {
+        let time = format!("{}", time::now_utc().rfc3339());
+        self.write(Stream::StdOut, &json_event(event, &time, fields));
+    }
+
+    fn start(&mut self, header: &str) {
+        if self.mode == Mode::Jsonl {
+            self.event("host_started", &[("host", json_string(header))]);
         }
+
+        // Watch starts each host again for every rebuild, and the summary
+        // should only report this one.
+        self.diagnostics.remove(header);
+        self.tails.remove(header);
+
+        // Hosts are tracked in jsonl mode as well, for host_finished to know
+        // whether they succeeded, but their output is not held back.
+        let buffered = self.mode != Mode::Interleaved || self.verbosity == Verbosity::Quiet;
+
+        if !buffered || self.running_mut(header).is_some() {
+            return;
+        }
+
+        self.running.push(Running {
+            header: header.into(),
+            lines: Vec::new(),
+            step: String::new(),
+            last_line: String::new(),
+            started: Instant::now(),
+            succeeded: None,
+            finished: false,
+        });
+
+        self.draw_status();
     }
 
-    fn format_header(&self, stream: Stream, header: &str) -> String {
-        let formatted = format!("[{}]", header);
+    fn step(&mut self, header: &str, step: &str) {
+        if self.mode == Mode::Jsonl {
+            self.event("step_started",
+                       &[("host", json_string(header)), ("step", json_string(step))]);
+        }
 
-        if self.use_color(stream) {
-            Style::new().bold().paint(formatted).to_string()
+        if let Some(running) = self.running_mut(header) {
+            running.step = step.into();
+        }
+
+        self.draw_status();
+    }
+
+    fn finish(&mut self, header: &str) {
+        let index = match self.running.iter().position(|running| running.header == header) {
+            Some(index) => index,
+            None => return,
+        };
+
+        if self.mode == Mode::Jsonl {
+            let success = self.running[index].succeeded == Some(true);
+            self.event("host_finished",
+                       &[("host", json_string(header)), ("success", success.to_string())]);
+            self.running.remove(index);
+            return;
+        }
+
+        self.clear_status();
+        self.running[index].finished = true;
+
+        // Quiet mode and the dashboard only show the output of failed hosts.
+        let failed = self.running[index].succeeded != Some(true);
+        let lines = self.running[index].lines.split_off(0);
+        let show = failed ||
+                   (self.mode != Mode::Dashboard && self.verbosity != Verbosity::Quiet);
+
+        if show {
+            for &(stream, ref formatted) in &lines {
+                self.write(stream, formatted);
+            }
+        }
+
+        if self.mode == Mode::Dashboard {
+            // Rows stay on the dashboard until every host is done, then the
+            // final state is left on screen.
+            if self.running.iter().all(|running| running.finished) {
+                for row in self.rows() {
+                    self.write(Stream::StdOut, &row);
+                }
+                self.running.clear();
+            }
         } else {
-            formatted
+            self.running.remove(index);
+        }
+
+        self.draw_status();
+    }
+
+    fn rows(&self) -> Vec<String> {
+        let width = terminal_width();
+        let header_width = self.running
+                               .iter()
+                               .map(|running| running.header.len())
+                               .max()
+                               .unwrap_or(0);
+
+        self.running
+            .iter()
+            .map(|running| {
+                let state = match (running.finished, running.succeeded) {
+                    (true, Some(true)) => "succeeded".to_string(),
+                    (true, _) => "failed".to_string(),
+                    (false, _) => running.step.clone(),
+                };
+
+                let row = format!("[{:<width$}] {:<14} {:>6}  {}",
+                                  running.header,
+                                  state,
+                                  format_elapsed(&running.started),
+                                  running.last_line.trim(),
+                                  width = header_width);
+                truncate(&row, width)
+            })
+            .collect()
+    }
+
+    // Grouped mode shows a single line listing the hosts still running;
+    // the dashboard shows a row per host.
+    fn status(&self) -> Vec<String> {
+        match self.mode {
+            Mode::Dashboard => self.rows(),
+            _ => {
+                let hosts: Vec<String> = self.running
+                                             .iter()
+                                             .map(|running| {
+                                                 format!("{} ({} lines)",
+                                                         running.header,
+                                                         running.lines.len())
+                                             })
+                                             .collect();
+
+                vec![truncate(&format!("Running: {}", hosts.join(", ")), terminal_width())]
+            }
         }
     }
 
-    fn line(&self, stream: Stream, header: &str, msg: &str) {
-        let formatted = format!("{} {}",
-                                self.format_header(stream, header),
-                                msg.trim_right());
+    fn draw_status(&mut self) {
+        let drawn = self.mode == Mode::Grouped || self.mode == Mode::Dashboard;
+
+        if !self.stdout_is_tty || !drawn || self.running.is_empty() {
+            return;
+        }
+
+        self.clear_status();
+
+        let rows = self.status();
+        write!(io::stdout(), "{}", rows.join("\n")).expect("failed to write stdout");
+        io::stdout().flush().ok();
+        self.status_rows = rows.len();
+    }
+
+    // The status is drawn without a trailing newline, so the cursor is on
+    // its last row.
+    fn clear_status(&mut self) {
+        if self.status_rows == 0 {
+            return;
+        }
+
+        if self.status_rows > 1 {
+            write!(io::stdout(), "\x1b[{}A", self.status_rows - 1).expect("failed to write stdout");
+        }
+
+        write!(io::stdout(), "\r\x1b[J").expect("failed to write stdout");
+        self.status_rows = 0;
+    }
+
+    fn write(&self, stream: Stream, formatted: &str) {
+        capture(formatted);
 
         match stream {
             Stream::StdOut => {
@@ -57,6 +616,168 @@ impl RawLog {
         }
     }
 
+    fn scan(&mut self, header: &str, line: &str) {
+        let max_errors = self.diagnostics_config.max_errors;
+        let diagnostics = self.diagnostics
+                              .entry(header.into())
+                              .or_insert_with(|| Diagnostics::new(max_errors));
+
+        diagnostics.scan(&self.diagnostics_config.matchers, line);
+
+        let tail_lines = self.tail_lines;
+        let tail = self.tails.entry(header.into()).or_insert_with(VecDeque::new);
+
+        while !tail.is_empty() && tail.len() >= tail_lines {
+            tail.pop_front();
+        }
+
+        if tail_lines > 0 {
+            tail.push_back(strip_ansi(line));
+        }
+    }
+
+    fn timestamp(&self) -> Option<String> {
+        match self.timestamps {
+            Some(Timestamps::Wall) => {
+                Some(format!("{}", time::now().strftime("%H:%M:%S").unwrap()))
+            }
+            Some(Timestamps::Relative) => {
+                Some(format!("+{}", format_duration(&self.run_started.elapsed())))
+            }
+            None => None,
+        }
+    }
+
+    fn record(&mut self, header: &str, text: &str) {
+        let text = strip_ansi(text);
+        let stamped = match self.timestamp() {
+            Some(timestamp) => format!("{} {}", timestamp, text),
+            None => text.clone(),
+        };
+
+        if let Some(ref mut files) = self.files {
+            files.write(header, &stamped);
+        }
+
+        if let Some(running) = self.running_mut(header) {
+            running.last_line = text;
+        }
+    }
+
+    fn set_color(&mut self, choice: ColorChoice) {
+        let no_color = env::var("NO_COLOR").ok();
+        let clicolor_force = env::var("CLICOLOR_FORCE").ok();
+
+        self.stdout_color = should_color(choice,
+                                         self.stdout_is_tty,
+                                         no_color.clone(),
+                                         clicolor_force.clone());
+        self.stderr_color = should_color(choice, self.stderr_is_tty, no_color, clicolor_force);
+    }
+
+    fn use_color(&self, stream: Stream) -> bool {
+        match stream {
+            Stream::StdOut => self.stdout_color,
+            Stream::StdErr => self.stderr_color,
+        }
+    }
+
+    fn header_style(&mut self, header: &str) -> Style {
+        if header == "local" {
+            return Style::new().bold();
+        }
+
+        let next = HEADER_COLORS[self.header_colors.len() % HEADER_COLORS.len()];
+        self.header_colors.entry(header.into()).or_insert(next).bold()
+    }
+
+    fn format_header(&mut self, stream: Stream, header: &str) -> String {
+        let formatted = format!("[{}]", header);
+
+        if self.use_color(stream) {
+            self.header_style(header).paint(formatted).to_string()
+        } else {
+            formatted
+        }
+    }
+
+    fn line(&mut self, stream: Stream, header: &str, msg: &str) {
+        if self.mode == Mode::Jsonl {
+            self.event("line",
+                       &[("host", json_string(header)),
+                         ("stream", json_string(stream_name(stream))),
+                         ("text", json_string(&strip_ansi(msg.trim_right())))]);
+            return;
+        }
+
+        let mut formatted = format!("{} {}",
+                                    self.format_header(stream, header),
+                                    msg.trim_right());
+
+        if let Some(timestamp) = self.timestamp() {
+            formatted = format!("{} {}", self.format_timestamp(stream, &timestamp), formatted);
+        }
+
+        if let Some(running) = self.running_mut(header) {
+            running.lines.push((stream, formatted));
+        } else {
+            self.clear_status();
+            self.write(stream, &formatted);
+        }
+
+        self.draw_status();
+    }
+
+    fn format_timestamp(&self, stream: Stream, timestamp: &str) -> String {
+        if self.use_color(stream) {
+            Style::new().dimmed().paint(timestamp).to_string()
+        } else {
+            timestamp.into()
+        }
+    }
+
+    // The duration is only shown along with timestamps, to keep the default
+    // output as it is.
+    fn command_finished(&mut self, header: &str, code: Option<i32>, duration: &Duration) {
+        let id = match self.cmds.remove(header) {
+            Some(id) => id,
+            None => return,
+        };
+
+        // Like command_started, which is only sent for commands that are
+        // shown.
+        if self.mode == Mode::Jsonl {
+            let id = match id {
+                Some(id) => id,
+                None => return,
+            };
+
+            let code = code.map_or("null".to_string(), |code| code.to_string());
+            let secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+
+            self.event("command_finished",
+                       &[("id", id.to_string()),
+                         ("host", json_string(header)),
+                         ("exit_code", code),
+                         ("duration", format!("{:.3}", secs))]);
+            return;
+        }
+
+        if self.timestamps.is_none() {
+            return;
+        }
+
+        let stream = Stream::StdOut;
+        let msg = format!("(took {})", format_duration(duration));
+        let formatted = self.format_timestamp(stream, &msg);
+
+        self.record(header, &msg);
+
+        if id.is_some() {
+            self.output_line(stream, header, &formatted);
+        }
+    }
+
     fn format_cmd(&self, stream: Stream, cmd: &str) -> String {
         let formatted = format!("$ {}", cmd);
 
@@ -67,9 +788,55 @@ impl RawLog {
         }
     }
 
-    fn cmd(&self, header: &str, cmd: &str) {
+    // In quiet mode, only errors, successes and the output of hosts that
+    // fail are shown.
+    fn hidden(&mut self, header: &str) -> bool {
+        self.verbosity == Verbosity::Quiet && self.running_mut(header).is_none()
+    }
+
+    fn output_line(&mut self, stream: Stream, header: &str, msg: &str) {
+        if !self.hidden(header) {
+            self.line(stream, header, msg);
+        }
+    }
+
+    fn plumbing(&mut self, header: &str, cmd: &str) {
+        if self.verbosity >= Verbosity::Verbose {
+            self.cmd(header, cmd);
+        } else {
+            self.tails.remove(header);
+            self.cmds.insert(header.into(), None);
+        }
+    }
+
+    fn trace(&mut self, header: &str, msg: &str) {
+        if self.verbosity >= Verbosity::VeryVerbose {
+            self.output_line(Stream::StdOut, header, msg);
+        }
+    }
+
+    fn cmd(&mut self, header: &str, cmd: &str) {
+        self.tails.remove(header);
+
+        if self.hidden(header) {
+            self.cmds.insert(header.into(), None);
+            return;
+        }
+
+        self.cmd_count += 1;
+        self.cmds.insert(header.into(), Some(self.cmd_count));
+
+        if self.mode == Mode::Jsonl {
+            self.event("command_started",
+                       &[("id", self.cmd_count.to_string()),
+                         ("host", json_string(header)),
+                         ("command", json_string(cmd))]);
+            return;
+        }
+
         let stream = Stream::StdOut;
-        self.line(stream, header, &self.format_cmd(stream, cmd));
+        let formatted = self.format_cmd(stream, cmd);
+        self.line(stream, header, &formatted);
     }
 
     fn format_error(&self, stream: Stream, msg: &str) -> String {
@@ -80,9 +847,14 @@ impl RawLog {
         }
     }
 
-    fn error(&self, header: &str, msg: &str) {
+    fn error(&mut self, header: &str, msg: &str) {
+        if let Some(running) = self.running_mut(header) {
+            running.succeeded = Some(false);
+        }
+
         let stream = Stream::StdErr;
-        self.line(stream, header, &self.format_error(stream, msg));
+        let formatted = self.format_error(stream, msg);
+        self.line(stream, header, &formatted);
     }
 
     fn format_success(&self, stream: Stream, msg: &str) -> String {
@@ -93,9 +865,16 @@ impl RawLog {
         }
     }
 
-    fn success(&self, header: &str, msg: &str) {
+    fn success(&mut self, header: &str, msg: &str) {
+        if let Some(running) = self.running_mut(header) {
+            if running.succeeded.is_none() {
+                running.succeeded = Some(true);
+            }
+        }
+
         let stream = Stream::StdOut;
-        self.line(stream, header, &self.format_success(stream, msg));
+        let formatted = self.format_success(stream, msg);
+        self.line(stream, header, &formatted);
     }
 }
 
@@ -106,7 +885,105 @@ pub struct Output {
 
 impl Output {
     pub fn new() -> Self {
-        Output { log: Arc::new(Mutex::new(RawLog::new())) }
+        let mut log = RawLog::new();
+        log.set_color(ColorChoice::Auto);
+
+        Output { log: Arc::new(Mutex::new(log)) }
+    }
+
+    fn lock(&self) -> MutexGuard<RawLog> {
+        self.log.lock().expect("failed to acquire log mutex")
+    }
+
+    pub fn log_to(&self, files: LogFiles) {
+        self.lock().files = Some(files);
+    }
+
+    pub fn set_color(&self, choice: ColorChoice) {
+        self.lock().set_color(choice);
+    }
+
+    pub fn set_diagnostics(&self, config: &DiagnosticsConfig) {
+        self.lock().diagnostics_config = config.clone();
+    }
+
+    pub fn diagnostics(&self, header: &str) -> Option<Diagnostics> {
+        self.lock().diagnostics.get(header).cloned()
+    }
+
+    pub fn tail(&self, header: &str) -> Vec<String> {
+        match self.lock().tails.get(header) {
+            Some(tail) => tail.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn set_tail_lines(&self, tail_lines: usize) {
+        self.lock().tail_lines = tail_lines;
+    }
+
+    pub fn set_verbosity(&self, verbosity: Verbosity) {
+        self.lock().verbosity = verbosity;
+    }
+
+    pub fn set_timestamps(&self, timestamps: Timestamps) {
+        self.lock().timestamps = Some(timestamps);
+    }
+
+    // Marks the end of the run in jsonl mode.
+    pub fn finish(&self, exit_code: i32) {
+        let log = self.lock();
+
+        if log.mode == Mode::Jsonl {
+            log.event("run_finished", &[("exit_code", exit_code.to_string())]);
+        }
+    }
+
+    // Marks the end of a run that stopped on an error, which main prints
+    // and exits with 1 for.
+    pub fn fail(&self, msg: &str) {
+        let log = self.lock();
+
+        if log.mode == Mode::Jsonl {
+            log.event("run_finished",
+                      &[("exit_code", "1".into()), ("error", json_string(msg))]);
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.lock().mode
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        let mut log = self.lock();
+
+        // Events are for programs to read, so text in them is never colored.
+        if mode == Mode::Jsonl {
+            log.mode = mode;
+            log.stdout_color = false;
+            log.stderr_color = false;
+            log.event("run_started", &[]);
+            return;
+        }
+
+        // The dashboard redraws in place, which only makes sense on a
+        // terminal.
+        if mode == Mode::Dashboard && !log.stdout_is_tty {
+            log.mode = Mode::Interleaved;
+            return;
+        }
+
+        log.mode = mode;
+
+        if mode == Mode::Dashboard {
+            let ticker = self.log.clone();
+
+            // Keep elapsed times moving while hosts are quiet.
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                ticker.lock().expect("failed to acquire log mutex").draw_status();
+            });
+        }
     }
 }
 
@@ -128,33 +1005,84 @@ impl Log {
         self.log.lock().expect("failed to acquire log mutex")
     }
 
+    // In grouped mode, output between start and finish is held back and
+    // printed as one block when the host finishes.
+    pub fn start(&self) {
+        self.lock().start(&self.header);
+    }
+
+    pub fn finish(&self) {
+        self.lock().finish(&self.header);
+    }
+
+    pub fn step(&self, step: &str) {
+        self.lock().step(&self.header, step);
+    }
+
+    pub fn command_finished(&self, code: Option<i32>, duration: &Duration) {
+        self.lock().command_finished(&self.header, code, duration);
+    }
+
     pub fn line(&self, stream: Stream, msg: &str) {
-        self.lock().line(stream, &self.header, msg);
+        let mut log = self.lock();
+        log.record(&self.header, msg);
+        log.output_line(stream, &self.header, msg);
+    }
+
+    // The summary is what quiet mode leaves on screen, so it is shown
+    // whatever the verbosity.
+    pub fn summary_line(&self, stream: Stream, msg: &str) {
+        let mut log = self.lock();
+        log.record(&self.header, msg);
+        log.line(stream, &self.header, msg);
     }
 
     pub fn cmd(&self, cmd: &str) {
-        self.lock().cmd(&self.header, cmd);
+        let mut log = self.lock();
+        log.record(&self.header, &format!("$ {}", cmd));
+        log.cmd(&self.header, cmd);
+    }
+
+    // bran's own commands (git plumbing, resets, copies), shown with -v.
+    pub fn plumbing(&self, cmd: &str) {
+        let mut log = self.lock();
+        log.record(&self.header, &format!("$ {}", cmd));
+        log.plumbing(&self.header, cmd);
+    }
+
+    // Low level details such as full ssh command lines, shown with -vv.
+    pub fn trace(&self, msg: &str) {
+        let mut log = self.lock();
+        log.record(&self.header, msg);
+        log.trace(&self.header, msg);
     }
 
     pub fn error(&self, msg: &str) {
-        self.lock().error(&self.header, msg);
+        let mut log = self.lock();
+        log.record(&self.header, msg);
+        log.error(&self.header, msg);
     }
 
     pub fn success(&self, msg: &str) {
-        self.lock().success(&self.header, msg);
+        let mut log = self.lock();
+        log.record(&self.header, msg);
+        log.success(&self.header, msg);
     }
 
     pub fn stream<R: Read>(&self, reader: R, stream: Stream) {
         let mut buf_reader = BufReader::new(reader);
+        let keep_ansi = self.lock().use_color(stream);
 
         loop {
-            let mut line = String::new();
+            let mut line = Vec::new();
 
-            match buf_reader.read_line(&mut line) {
+            match buf_reader.read_until(b'\n', &mut line) {
                 Ok(0) => {
                     break;
                 }
                 Ok(_) => {
+                    let line = decode_line(&line, keep_ansi);
+                    self.lock().scan(&self.header, &line);
                     self.line(stream, &line);
                 }
                 Err(err) => {
@@ -165,3 +1093,221 @@ impl Log {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+    use libc;
+    use summary::{self, Summary};
+    use super::{decode_line, is_run_dir, json_event, json_string, should_color, strip_ansi,
+                ColorChoice, Log, LogFiles, Mode, Output, Stream, Verbosity, WRITTEN};
+
+    fn command_events() -> Vec<String> {
+        WRITTEN.with(|written| {
+            written.borrow_mut()
+                   .drain(..)
+                   .filter(|line| line.starts_with("{\"event\":\"command_"))
+                   .collect()
+        })
+    }
+
+    #[test]
+    fn color_follows_tty_by_default() {
+        assert!(should_color(ColorChoice::Auto, true, None, None));
+        assert!(!should_color(ColorChoice::Auto, false, None, None));
+    }
+
+    #[test]
+    fn no_color_disables_color() {
+        assert!(!should_color(ColorChoice::Auto, true, Some("1".into()), None));
+        assert!(should_color(ColorChoice::Auto, true, Some("".into()), None));
+    }
+
+    #[test]
+    fn clicolor_force_enables_color() {
+        assert!(should_color(ColorChoice::Auto, false, None, Some("1".into())));
+        assert!(!should_color(ColorChoice::Auto, false, None, Some("0".into())));
+    }
+
+    #[test]
+    fn explicit_choice_wins() {
+        assert!(should_color(ColorChoice::Always, false, Some("1".into()), None));
+        assert!(!should_color(ColorChoice::Never, true, None, Some("1".into())));
+    }
+
+    #[test]
+    fn strips_ansi() {
+        assert_eq!(strip_ansi("\x1b[1;31merror\x1b[0m: oops"), "error: oops");
+        assert_eq!(strip_ansi("\x1b]0;title\x07text"), "text");
+        assert_eq!(strip_ansi("\x1b]8;;http://x\x1b\\link"), "link");
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_lossily() {
+        assert_eq!(decode_line(b"caf\xe9\n", false), "caf\u{fffd}");
+    }
+
+    #[test]
+    fn collapses_carriage_returns() {
+        assert_eq!(decode_line(b" 10%\r 50%\r100%\r\n", false), "100%");
+        assert_eq!(decode_line(b"done\r\n", false), "done");
+    }
+
+    #[test]
+    fn keeps_ansi_when_asked() {
+        assert_eq!(decode_line(b"\x1b[32mok\x1b[0m\n", true), "\x1b[32mok\x1b[0m");
+        assert_eq!(decode_line(b"\x1b[32mok\x1b[0m\n", false), "ok");
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a \"b\" \\ c"), "\"a \\\"b\\\" \\\\ c\"");
+        assert_eq!(json_string("tab\tbell\x07"), "\"tab\\tbell\\u0007\"");
+    }
+
+    #[test]
+    fn recognizes_run_dirs() {
+        assert!(is_run_dir("20160912-103000"));
+        assert!(is_run_dir("20160912-103000-2"));
+        assert!(!is_run_dir("2016-09-12"));
+        assert!(!is_run_dir("20160912-103000-"));
+        assert!(!is_run_dir("Documents"));
+    }
+
+    #[test]
+    fn prunes_only_run_dirs() {
+        let root = env::temp_dir().join(format!("bran-logs-{}", unsafe { libc::getpid() }));
+        fs::remove_dir_all(&root).ok();
+
+        for dir in &["20160912-103000", "20160912-103001", "src", "99999999"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        // Both runs started in the same second get their own directory.
+        LogFiles::create(&root, 2).unwrap();
+        LogFiles::create(&root, 2).unwrap();
+
+        let mut left: Vec<String> = fs::read_dir(&root)
+                                        .unwrap()
+                                        .map(|entry| {
+                                            entry.unwrap().file_name().to_string_lossy().into()
+                                        })
+                                        .collect();
+        left.sort();
+
+        assert_eq!(left.len(), 4);
+        assert_eq!(&left[2..], ["99999999", "src"]);
+        assert!(left[0] != left[1] && left[0] > "20160912-103001".to_string());
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn pairs_command_events() {
+        let output = Output::new();
+        output.set_mode(Mode::Jsonl);
+
+        let log = Log::new("linux", &output);
+        let took = Duration::from_millis(1500);
+
+        // bran's own commands are hidden by default, and some queries are
+        // never announced at all.
+        log.plumbing("git reset --hard");
+        log.command_finished(Some(0), &took);
+        log.command_finished(Some(0), &took);
+        assert_eq!(command_events(), Vec::<String>::new());
+
+        log.cmd("make");
+        log.command_finished(Some(2), &took);
+
+        let events = command_events();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].contains("\"event\":\"command_started\","));
+        assert!(events[0].contains(",\"id\":1,"));
+        assert!(events[1].contains("\"event\":\"command_finished\","));
+        assert!(events[1].contains(",\"id\":1,"));
+        assert!(events[1].contains(",\"exit_code\":2,"));
+
+        // Quiet mode hides commands of hosts that aren't running.
+        output.set_verbosity(Verbosity::Quiet);
+        log.cmd("make");
+        log.command_finished(Some(0), &took);
+        assert_eq!(command_events(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn keeps_the_tail_of_the_last_command() {
+        let output = Output::new();
+        output.set_tail_lines(2);
+
+        let log = Log::new("linux", &output);
+        log.cmd("cmake .");
+        log.stream(&b"-- Configuring done\n"[..], Stream::StdOut);
+        log.cmd("make");
+        log.stream(&b"[ 10%] a.o\n[ 20%] b.o\nerror: oops\n"[..], Stream::StdOut);
+
+        assert_eq!(output.tail("linux"), ["[ 20%] b.o", "error: oops"]);
+
+        output.set_tail_lines(0);
+        log.cmd("make");
+        log.stream(&b"error: oops\n"[..], Stream::StdErr);
+
+        assert_eq!(output.tail("linux"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn forgets_diagnostics_of_earlier_runs() {
+        let output = Output::new();
+        let log = Log::new("linux", &output);
+
+        log.start();
+        log.cmd("make");
+        log.stream(&b"main.c:4:10: error: oops\n"[..], Stream::StdErr);
+        log.finish();
+        assert_eq!(output.diagnostics("linux").map(|found| found.error_count), Some(1));
+
+        log.start();
+        assert!(output.diagnostics("linux").is_none());
+        assert_eq!(output.tail("linux"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn shows_the_summary_in_quiet_mode() {
+        let output = Output::new();
+        output.set_verbosity(Verbosity::Quiet);
+
+        let log = Log::new("linux", &output);
+        log.cmd("make");
+        log.stream(&b"main.c:4:10: error: oops\nmake: *** [all] Error 1\n"[..],
+                   Stream::StdErr);
+
+        WRITTEN.with(|written| written.borrow_mut().clear());
+        summary::print("Build", &[Summary::new("linux", false)], &output);
+
+        let written = WRITTEN.with(|written| written.borrow_mut().split_off(0));
+        assert!(written.contains(&"[linux]   main.c:4:10: oops".to_string()));
+        assert!(written.contains(&"[linux]   1 error, 0 warnings".to_string()));
+        assert!(written.contains(&"[linux]     make: *** [all] Error 1".to_string()));
+    }
+
+    #[test]
+    fn finishes_failed_runs() {
+        let output = Output::new();
+        output.set_mode(Mode::Jsonl);
+        output.fail("Failed to commit");
+
+        let written = WRITTEN.with(|written| written.borrow_mut().split_off(0));
+        let last = written.last().expect("should have written events");
+        assert!(last.starts_with("{\"event\":\"run_finished\","));
+        assert!(last.ends_with(",\"exit_code\":1,\"error\":\"Failed to commit\"}"));
+    }
+
+    #[test]
+    fn formats_json_events() {
+        assert_eq!(json_event("line", "t", &[("host", json_string("a")), ("code", "1".into())]),
+                   "{\"event\":\"line\",\"time\":\"t\",\"host\":\"a\",\"code\":1}");
+    }
+}
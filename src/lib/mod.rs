@@ -1 +1,8 @@
-
+pub mod artifacts;
+pub mod build;
+pub mod summary;
+pub mod cancel;
+pub mod diagnostics;
+pub mod ssh_config;
+pub mod scaffold;
+pub mod value;
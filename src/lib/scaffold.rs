@@ -0,0 +1,213 @@
+use std::path::Path;
+
+// Kinds of project "bran init" knows how to build, by the file that marks
+// them, in the order they are looked for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProjectType {
+    Cargo,
+    CMake,
+    Make,
+    Npm,
+}
+
+const PROJECT_FILES: [(&'static str, ProjectType); 4] = [("Cargo.toml", ProjectType::Cargo),
+                                                         ("CMakeLists.txt", ProjectType::CMake),
+                                                         ("Makefile", ProjectType::Make),
+                                                         ("package.json", ProjectType::Npm)];
+
+impl ProjectType {
+    pub fn detect(dir: &Path) -> Option<ProjectType> {
+        PROJECT_FILES.iter()
+                     .find(|&&(file, _)| dir.join(file).is_file())
+                     .map(|&(_, project_type)| project_type)
+    }
+
+    pub fn file(&self) -> &'static str {
+        PROJECT_FILES.iter()
+                     .find(|&&(_, project_type)| project_type == *self)
+                     .map(|&(file, _)| file)
+                     .unwrap()
+    }
+
+    pub fn build_commands(&self) -> Vec<String> {
+        let commands: &[&str] = match *self {
+            ProjectType::Cargo => &["cargo build"],
+            ProjectType::CMake => &["cmake -H. -Bbuild", "cmake --build build"],
+            ProjectType::Make => &["make"],
+            ProjectType::Npm => &["npm install", "npm run build"],
+        };
+
+        commands.iter().map(|command| command.to_string()).collect()
+    }
+}
+
+// A host as given to "bran init", in the same user@address:build_dir form as
+// an scp destination.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostSpec {
+    pub name: String,
+    pub user: String,
+    pub host: Option<String>,
+    pub build_dir: String,
+}
+
+impl HostSpec {
+    // Hosts are named after the first part of their address, so that
+    // linux.build.example.com is "linux".
+    pub fn parse(spec: &str) -> Result<HostSpec, String> {
+        let invalid = || format!("invalid host \"{}\", expected user@address:build_dir", spec);
+
+        let at = try!(spec.find('@').ok_or_else(&invalid));
+        let colon = try!(spec[at..].find(':').map(|colon| at + colon).ok_or_else(&invalid));
+
+        let user = &spec[..at];
+        let address = &spec[at + 1..colon];
+        let build_dir = &spec[colon + 1..];
+
+        if user.is_empty() || address.is_empty() || build_dir.is_empty() {
+            return Err(invalid());
+        }
+
+        let name = address.split('.').next().unwrap_or(address);
+
+        Ok(HostSpec {
+            name: name.into(),
+            user: user.into(),
+            host: if name == address { None } else { Some(address.into()) },
+            build_dir: build_dir.into(),
+        })
+    }
+}
+
+// Plain YAML scalars can't start with an indicator or look like another
+// type, so anything unusual is double quoted.
+fn scalar(value: &str) -> String {
+    let plain = !value.is_empty() &&
+                value.chars().all(|c| c.is_alphanumeric() || "-_./~ =+".contains(c)) &&
+                !value.starts_with(|c: char| c == '-' || c == '~' || c == ' ' || c.is_numeric()) &&
+                !value.ends_with(' ') && !["true", "false", "null", "yes", "no"].contains(&value);
+
+    if plain {
+        value.into()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+// Hosts that would share a name are named after their whole address
+// instead, and numbered if even that is the same.
+fn host_names(hosts: &[HostSpec]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+
+    for host in hosts {
+        let shared = hosts.iter().filter(|other| other.name == host.name).count() > 1;
+        let base = match host.host {
+            Some(ref address) if shared => address.clone(),
+            _ => host.name.clone(),
+        };
+
+        let mut name = base.clone();
+        let mut count = 1;
+
+        while names.contains(&name) {
+            count += 1;
+            name = format!("{}-{}", base, count);
+        }
+
+        names.push(name);
+    }
+
+    names
+}
+
+pub fn render(hosts: &[HostSpec], build: &[String]) -> String {
+    let mut yaml = String::from("hosts:\n");
+
+    for (host, name) in hosts.iter().zip(host_names(hosts)) {
+        yaml.push_str(&format!("  {}:\n", scalar(&name)));
+
+        let address = host.host.as_ref().unwrap_or(&host.name);
+
+        if *address != name {
+            yaml.push_str(&format!("    host: {}\n", scalar(address)));
+        }
+
+        yaml.push_str(&format!("    user: {}\n", scalar(&host.user)));
+        yaml.push_str(&format!("    build_dir: {}\n", scalar(&host.build_dir)));
+    }
+
+    yaml.push_str("build:\n");
+
+    for command in build {
+        yaml.push_str(&format!("  - {}\n", scalar(command)));
+    }
+
+    yaml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, HostSpec, ProjectType};
+    use value::{parse, Format};
+
+    #[test]
+    fn parses_host_specs() {
+        assert_eq!(HostSpec::parse("hodor@linux.example.com:/build/winterfell"),
+                   Ok(HostSpec {
+                       name: "linux".into(),
+                       user: "hodor".into(),
+                       host: Some("linux.example.com".into()),
+                       build_dir: "/build/winterfell".into(),
+                   }));
+        assert_eq!(HostSpec::parse("hodor@win:build").map(|host| host.host), Ok(None));
+        assert!(HostSpec::parse("linux:/build").is_err());
+        assert!(HostSpec::parse("hodor@linux").is_err());
+    }
+
+    #[test]
+    fn proposes_build_commands() {
+        assert_eq!(ProjectType::Cargo.build_commands(), ["cargo build"]);
+        assert_eq!(ProjectType::Npm.file(), "package.json");
+    }
+
+    #[test]
+    fn renders_parseable_config() {
+        let hosts = [HostSpec::parse("hodor@linux.example.com:/build/winterfell").unwrap(),
+                     HostSpec::parse("hodor@win:C:/build").unwrap()];
+        let build = ["make -j8".to_string(), "echo \"done: ok\"".to_string(), "true".to_string()];
+
+        let yaml = render(&hosts, &build);
+        let value = parse(Format::Yaml, &yaml).expect("should parse");
+
+        assert_eq!(value["hosts"]["linux"]["host"].as_str(), Some("linux.example.com"));
+        assert_eq!(value["hosts"]["win"]["build_dir"].as_str(), Some("C:/build"));
+        assert!(value["hosts"]["win"]["host"].is_missing());
+        assert_eq!(value["build"][0].as_str(), Some("make -j8"));
+        assert_eq!(value["build"][1].as_str(), Some("echo \"done: ok\""));
+        assert_eq!(value["build"][2].as_str(), Some("true"));
+    }
+
+    #[test]
+    fn renames_hosts_that_share_a_name() {
+        let hosts = [HostSpec::parse("a@linux.example.com:/build").unwrap(),
+                     HostSpec::parse("b@linux.example.org:/build").unwrap(),
+                     HostSpec::parse("c@linux.example.org:/other").unwrap(),
+                     HostSpec::parse("d@win:/build").unwrap(),
+                     HostSpec::parse("e@win:/other").unwrap()];
+
+        let yaml = render(&hosts, &["make".to_string()]);
+        let value = parse(Format::Yaml, &yaml).expect("should parse");
+
+        assert_eq!(value["hosts"]["linux.example.com"]["user"].as_str(), Some("a"));
+        assert!(value["hosts"]["linux.example.com"]["host"].is_missing());
+        assert_eq!(value["hosts"]["linux.example.org"]["user"].as_str(), Some("b"));
+        assert_eq!(value["hosts"]["linux.example.org-2"]["user"].as_str(), Some("c"));
+        assert_eq!(value["hosts"]["linux.example.org-2"]["host"].as_str(),
+                   Some("linux.example.org"));
+        assert_eq!(value["hosts"]["win"]["user"].as_str(), Some("d"));
+        assert!(value["hosts"]["win"]["host"].is_missing());
+        assert_eq!(value["hosts"]["win-2"]["user"].as_str(), Some("e"));
+        assert_eq!(value["hosts"]["win-2"]["host"].as_str(), Some("win"));
+        assert!(value["hosts"]["linux"].is_missing());
+    }
+}
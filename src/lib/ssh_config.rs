@@ -0,0 +1,209 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+// What ~/.ssh/config says about a host, for the settings bran.yml leaves
+// out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SshHost {
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Block {
+    patterns: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+// Just enough of OpenSSH's client configuration to look hosts up the way ssh
+// does: every block whose patterns match applies, and the first value found
+// for an option wins. "Match" blocks are never applied.
+#[derive(Clone, Debug, Default)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+}
+
+fn unquote(value: &str) -> &str {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+// Patterns can use * and ? as in a shell.
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&'*') => (0..name.len() + 1).any(|skip| glob(&pattern[1..], &name[skip..])),
+        Some(&c) => {
+            !name.is_empty() && (c == '?' || c == name[0]) && glob(&pattern[1..], &name[1..])
+        }
+    }
+}
+
+fn matches(patterns: &[String], name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let mut matched = false;
+
+    for pattern in patterns {
+        let negated = pattern.starts_with('!');
+        let chars: Vec<char> = pattern.trim_left_matches('!').chars().collect();
+
+        if glob(&chars, &name) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+fn expand_home(path: &str) -> String {
+    match home_dir() {
+        Some(ref home) if path.starts_with("~/") => {
+            home.join(&path[2..]).to_string_lossy().into_owned()
+        }
+        _ => path.into(),
+    }
+}
+
+impl SshConfig {
+    pub fn parse(contents: &str) -> SshConfig {
+        // Options before the first Host apply to every host.
+        let mut blocks = vec![Block {
+                                  patterns: vec!["*".into()],
+                                  options: Vec::new(),
+                              }];
+
+        for line in contents.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let split = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+            let keyword = line[..split].to_lowercase();
+            let value = line[split..].trim_left_matches(|c: char| c.is_whitespace() || c == '=');
+
+            match keyword.as_str() {
+                "host" => {
+                    blocks.push(Block {
+                        patterns: value.split_whitespace().map(|p| unquote(p).into()).collect(),
+                        options: Vec::new(),
+                    })
+                }
+                "match" => {
+                    blocks.push(Block {
+                        patterns: Vec::new(),
+                        options: Vec::new(),
+                    })
+                }
+                _ => {
+                    let block = blocks.last_mut().expect("there is always a block");
+                    block.options.push((keyword, unquote(value.trim()).into()));
+                }
+            }
+        }
+
+        SshConfig { blocks: blocks }
+    }
+
+    // The user's ~/.ssh/config, or nothing if there isn't one.
+    pub fn read() -> SshConfig {
+        let path = match home_dir() {
+            Some(home) => home.join(".ssh").join("config"),
+            None => return SshConfig::default(),
+        };
+
+        let mut contents = String::new();
+        match File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            Ok(_) => SshConfig::parse(&contents),
+            Err(_) => SshConfig::default(),
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> SshHost {
+        let mut host = SshHost::default();
+
+        for block in self.blocks.iter().filter(|block| matches(&block.patterns, name)) {
+            for &(ref keyword, ref value) in &block.options {
+                match keyword.as_str() {
+                    "user" if host.user.is_none() => host.user = Some(value.clone()),
+                    "port" if host.port.is_none() => host.port = value.parse().ok(),
+                    "identityfile" if host.identity_file.is_none() => {
+                        host.identity_file = Some(expand_home(value))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SshConfig, SshHost};
+
+    #[test]
+    fn looks_up_first_values() {
+        let config = SshConfig::parse("
+# Build machines
+Host linux-*
+    HostName %h.build.example.com
+    User builder
+    Port 2222
+
+Host linux-fast
+    User fast
+    IdentityFile=\"/keys/build\"
+
+Host *
+    User nobody
+");
+
+        assert_eq!(config.lookup("linux-fast"),
+                   SshHost {
+                       user: Some("builder".into()),
+                       port: Some(2222),
+                       identity_file: Some("/keys/build".into()),
+                   });
+        assert_eq!(config.lookup("win").user, Some("nobody".into()));
+    }
+
+    #[test]
+    fn applies_leading_options_and_negations() {
+        let config = SshConfig::parse("
+Port 22
+Host * !bastion
+    User builder
+Match exec \"true\"
+    User matched
+");
+
+        assert_eq!(config.lookup("linux").user, Some("builder".into()));
+        assert_eq!(config.lookup("linux").port, Some(22));
+        assert_eq!(config.lookup("bastion").user, None);
+    }
+
+    #[test]
+    fn matches_globs() {
+        let config = SshConfig::parse("Host build-?? *.example.com\n  User a");
+
+        assert_eq!(config.lookup("build-01").user, Some("a".into()));
+        assert_eq!(config.lookup("build-001").user, None);
+        assert_eq!(config.lookup("vm.example.com").user, Some("a".into()));
+    }
+}
@@ -0,0 +1,116 @@
+use artifacts::Artifact;
+use diagnostics::Diagnostics;
+use log::{Log, Mode, Output, Stream};
+
+pub struct Summary {
+    pub name: String,
+    pub success: bool,
+    pub artifacts: Vec<Artifact>,
+    pub artifacts_error: Option<String>,
+    // Stopped early, by watch when newer changes came in.
+    pub cancelled: bool,
+}
+
+impl Summary {
+    pub fn new(name: &str, success: bool) -> Self {
+        Summary {
+            name: name.into(),
+            success: success,
+            artifacts: Vec::new(),
+            artifacts_error: None,
+            cancelled: false,
+        }
+    }
+}
+
+fn print_artifacts(log: &Log, artifacts: &[Artifact]) {
+    for artifact in artifacts {
+        log.summary_line(Stream::StdOut,
+                         &format!("  {} ({} bytes, sha256 {})",
+                                  artifact.path,
+                                  artifact.size,
+                                  artifact.sha256));
+    }
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, word)
+    } else {
+        format!("{} {}s", count, word)
+    }
+}
+
+fn print_diagnostics(log: &Log, diagnostics: &Diagnostics) {
+    for error in &diagnostics.errors {
+        let line = match error.location() {
+            Some(location) => format!("  {}: {}", location, error.message),
+            None => format!("  {}", error.message),
+        };
+
+        log.summary_line(Stream::StdOut, &line);
+    }
+
+    if diagnostics.error_count > 0 || diagnostics.warning_count > 0 {
+        log.summary_line(Stream::StdOut,
+                         &format!("  {}, {}",
+                                  plural(diagnostics.error_count, "error"),
+                                  plural(diagnostics.warning_count, "warning")));
+    }
+}
+
+fn print_tail(log: &Log, tail: &[String]) {
+    if tail.is_empty() {
+        return;
+    }
+
+    log.summary_line(Stream::StdOut, "  Last lines of output:");
+    for line in tail {
+        log.summary_line(Stream::StdOut, &format!("    {}", line));
+    }
+}
+
+pub fn print(task: &str, results: &[Summary], output: &Output) {
+    // The host_finished events already carry the results.
+    if output.mode() == Mode::Jsonl {
+        return;
+    }
+
+    println!("\n---------- Summary ----------\n");
+
+    for result in results {
+        let log = Log::new(&result.name, output);
+
+        if result.success {
+            log.success(&format!("{} succeeded", task));
+        } else if result.cancelled {
+            log.summary_line(Stream::StdOut, &format!("{} cancelled", task));
+            continue;
+        } else {
+            log.error(&format!("{} failed", task));
+        }
+
+        if let Some(diagnostics) = output.diagnostics(&result.name) {
+            print_diagnostics(&log, &diagnostics);
+        }
+
+        if !result.success {
+            print_tail(&log, &output.tail(&result.name));
+        }
+
+        if let Some(ref err) = result.artifacts_error {
+            log.error(&format!("Fetching artifacts failed: {}", err));
+        }
+
+        print_artifacts(&log, &result.artifacts);
+    }
+}
+
+pub fn should_print(results: &[Summary]) -> bool {
+    results.len() > 1 ||
+    results.iter().any(|result| !result.artifacts.is_empty() || result.artifacts_error.is_some())
+}
+
+pub fn succeeded(results: &[Summary]) -> bool {
+    results.iter().all(|result| result.success && result.artifacts_error.is_none())
+}
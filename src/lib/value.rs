@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ops::Index;
+use std::path::Path;
+use rustc_serialize::json;
+use rustc_serialize::json::{Json, ParserError};
+use toml;
+use yaml_rust::{ScanError, Yaml, YamlLoader};
+use yaml_rust::yaml::Hash;
+
+pub type Table = BTreeMap<String, Value>;
+
+// A configuration value, whichever format it was read from. Looking up a
+// key or index that isn't there gives Missing, so lookups can be chained.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Real(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+    Null,
+    Missing,
+}
+
+static MISSING: Value = Value::Missing;
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_vec(&self) -> Option<&Vec<Value>> {
+        match *self {
+            Value::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Table> {
+        match *self {
+            Value::Table(ref table) => Some(table),
+            _ => None,
+        }
+    }
+
+    pub fn is_missing(&self) -> bool {
+        *self == Value::Missing
+    }
+}
+
+impl<'a> Index<&'a str> for Value {
+    type Output = Value;
+
+    fn index(&self, key: &'a str) -> &Value {
+        match self.as_table() {
+            Some(table) => table.get(key).unwrap_or(&MISSING),
+            None => &MISSING,
+        }
+    }
+}
+
+impl Index<usize> for Value {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        match self.as_vec() {
+            Some(values) => values.get(index).unwrap_or(&MISSING),
+            None => &MISSING,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+// Configuration file extensions, in the order they are looked for.
+pub const EXTENSIONS: [&'static str; 4] = ["yml", "yaml", "toml", "json"];
+
+impl Format {
+    // Anything that isn't TOML or JSON is read as YAML, as it always was.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+}
+
+// A file that could not be read into a value. Positions are lines and
+// columns from 1; errors about a key carry the path of the table it is in.
+#[derive(Debug)]
+pub struct ParseError {
+    pub msg: String,
+    pub position: Option<(usize, usize)>,
+    pub path: Vec<String>,
+}
+
+impl ParseError {
+    fn new(msg: &str, position: Option<(usize, usize)>) -> Self {
+        ParseError {
+            msg: msg.into(),
+            position: position,
+            path: Vec::new(),
+        }
+    }
+
+    fn keys_must_be_strings(path: &[String]) -> Self {
+        let msg = match path.last() {
+            Some(key) => format!("\"{}\" keys must be strings", key),
+            None => "keys must be strings".into(),
+        };
+
+        ParseError {
+            msg: msg,
+            position: None,
+            path: path.to_vec(),
+        }
+    }
+}
+
+// An empty file has no value at all, which is Missing.
+pub fn parse(format: Format, contents: &str) -> Result<Value, ParseError> {
+    match format {
+        Format::Yaml => parse_yaml(contents),
+        Format::Toml => parse_toml(contents),
+        Format::Json => parse_json(contents),
+    }
+}
+
+// Scan errors only say where they are in their message, which ends with
+// "at line L column C".
+fn scan_error(err: &ScanError) -> ParseError {
+    let text = err.to_string();
+    let mut words = text.rsplit(' ');
+    let mut position = None;
+
+    if let (Some(column), Some(_), Some(line)) = (words.next(), words.next(), words.next()) {
+        if let (Ok(line), Ok(column)) = (line.parse(), column.parse()) {
+            position = Some((line, column));
+        }
+    }
+
+    ParseError::new(err.description(), position)
+}
+
+fn parse_yaml(contents: &str) -> Result<Value, ParseError> {
+    let documents = try!(YamlLoader::load_from_str(contents).map_err(|err| scan_error(&err)));
+
+    match documents.into_iter().next() {
+        Some(document) => from_yaml(document, &mut Vec::new()),
+        None => Ok(Value::Missing),
+    }
+}
+
+fn from_yaml_hash(hash: Hash, path: &mut Vec<String>) -> Result<Value, ParseError> {
+    let mut table = Table::new();
+
+    for (key, value) in hash {
+        let key = match key {
+            Yaml::String(key) => key,
+            _ => return Err(ParseError::keys_must_be_strings(path)),
+        };
+
+        path.push(key.clone());
+        let value = try!(from_yaml(value, path));
+        path.pop();
+
+        table.insert(key, value);
+    }
+
+    Ok(Value::Table(table))
+}
+
+fn from_yaml(yaml: Yaml, path: &mut Vec<String>) -> Result<Value, ParseError> {
+    Ok(match yaml {
+        Yaml::String(value) => Value::String(value),
+        Yaml::Integer(value) => Value::Integer(value),
+        Yaml::Real(value) => value.parse().map(Value::Real).unwrap_or(Value::String(value)),
+        Yaml::Boolean(value) => Value::Boolean(value),
+        Yaml::Array(values) => {
+            let mut result = Vec::new();
+            for value in values {
+                result.push(try!(from_yaml(value, path)));
+            }
+            Value::Array(result)
+        }
+        Yaml::Hash(hash) => try!(from_yaml_hash(hash, path)),
+        Yaml::Null => Value::Null,
+        _ => Value::Missing,
+    })
+}
+
+fn parse_toml(contents: &str) -> Result<Value, ParseError> {
+    let mut parser = toml::Parser::new(contents);
+
+    // A TOML file of nothing but comments is empty, as it would be in YAML.
+    match parser.parse() {
+        Some(ref table) if table.is_empty() => Ok(Value::Missing),
+        Some(table) => Ok(from_toml(toml::Value::Table(table))),
+        None => {
+            let err = &parser.errors[0];
+            let (line, column) = parser.to_linecol(err.lo);
+            Err(ParseError::new(&err.desc, Some((line + 1, column + 1))))
+        }
+    }
+}
+
+// TOML dates have no counterpart elsewhere, so they are kept as strings.
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(value) => Value::String(value),
+        toml::Value::Integer(value) => Value::Integer(value),
+        toml::Value::Float(value) => Value::Real(value),
+        toml::Value::Boolean(value) => Value::Boolean(value),
+        toml::Value::Datetime(value) => Value::String(value),
+        toml::Value::Array(values) => Value::Array(values.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table) => {
+            Value::Table(table.into_iter().map(|(key, value)| (key, from_toml(value))).collect())
+        }
+    }
+}
+
+fn parse_json(contents: &str) -> Result<Value, ParseError> {
+    if contents.trim().is_empty() {
+        return Ok(Value::Missing);
+    }
+
+    match Json::from_str(contents) {
+        Ok(value) => Ok(from_json(value)),
+        Err(ParserError::SyntaxError(code, line, column)) => {
+            Err(ParseError::new(json::error_str(code), Some((line, column))))
+        }
+        Err(ParserError::IoError(err)) => Err(ParseError::new(&err.to_string(), None)),
+    }
+}
+
+fn from_json(value: Json) -> Value {
+    match value {
+        Json::String(value) => Value::String(value),
+        Json::I64(value) => Value::Integer(value),
+        Json::U64(value) if value <= i64::max_value() as u64 => Value::Integer(value as i64),
+        Json::U64(value) => Value::Real(value as f64),
+        Json::F64(value) => Value::Real(value),
+        Json::Boolean(value) => Value::Boolean(value),
+        Json::Array(values) => Value::Array(values.into_iter().map(from_json).collect()),
+        Json::Object(object) => {
+            Value::Table(object.into_iter().map(|(key, value)| (key, from_json(value))).collect())
+        }
+        Json::Null => Value::Null,
+    }
+}
+
+// For writing values back out as YAML, as "bran config show" does.
+pub fn to_yaml(value: &Value) -> Yaml {
+    match *value {
+        Value::String(ref value) => Yaml::String(value.clone()),
+        Value::Integer(value) => Yaml::Integer(value),
+        Value::Real(value) => Yaml::Real(value.to_string()),
+        Value::Boolean(value) => Yaml::Boolean(value),
+        Value::Array(ref values) => Yaml::Array(values.iter().map(to_yaml).collect()),
+        Value::Table(ref table) => {
+            let mut hash = Hash::new();
+            for (key, value) in table {
+                hash.insert(Yaml::String(key.clone()), to_yaml(value));
+            }
+            Yaml::Hash(hash)
+        }
+        Value::Null | Value::Missing => Yaml::Null,
+    }
+}
+
+pub fn to_json(value: &Value) -> Json {
+    match *value {
+        Value::String(ref value) => Json::String(value.clone()),
+        Value::Integer(value) => Json::I64(value),
+        Value::Real(value) => Json::F64(value),
+        Value::Boolean(value) => Json::Boolean(value),
+        Value::Array(ref values) => Json::Array(values.iter().map(to_json).collect()),
+        Value::Table(ref table) => {
+            Json::Object(table.iter().map(|(key, value)| (key.clone(), to_json(value))).collect())
+        }
+        Value::Null | Value::Missing => Json::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::as_pretty_json;
+    use super::{parse, to_json, Format, Value};
+
+    fn parse_all(yaml: &str, toml: &str, json: &str) -> Vec<Value> {
+        vec![parse(Format::Yaml, yaml).expect("should parse YAML"),
+             parse(Format::Toml, toml).expect("should parse TOML"),
+             parse(Format::Json, json).expect("should parse JSON")]
+    }
+
+    #[test]
+    fn formats_read_into_the_same_value() {
+        let values = parse_all("
+hosts:
+  linux:
+    user: a
+    build_dir: b
+build: [x, y]
+gc:
+  auto: 0",
+                               "
+build = [\"x\", \"y\"]
+
+[hosts.linux]
+user = \"a\"
+build_dir = \"b\"
+
+[gc]
+auto = 0",
+                               r#"{
+  "hosts": {"linux": {"user": "a", "build_dir": "b"}},
+  "build": ["x", "y"],
+  "gc": {"auto": 0}
+}"#);
+
+        assert_eq!(values[0], values[1]);
+        assert_eq!(values[0], values[2]);
+        assert_eq!(values[0]["hosts"]["linux"]["user"].as_str(), Some("a"));
+        assert_eq!(values[0]["build"][1].as_str(), Some("y"));
+        assert_eq!(values[0]["gc"]["auto"].as_i64(), Some(0));
+        assert!(values[0]["gc"]["max_history"].is_missing());
+    }
+
+    #[test]
+    fn empty_files_are_missing() {
+        for value in parse_all("# nothing", "# nothing", " ") {
+            assert!(value.is_missing());
+        }
+    }
+
+    #[test]
+    fn reports_syntax_error_positions() {
+        assert_eq!(parse(Format::Yaml, "a: [b").unwrap_err().position, Some((2, 1)));
+        assert_eq!(parse(Format::Toml, "a = 1\nb = ").unwrap_err().position.map(|p| p.0),
+                   Some(2));
+        assert_eq!(parse(Format::Json, "{\n  \"a\": }").unwrap_err().position.map(|p| p.0),
+                   Some(2));
+    }
+
+    #[test]
+    fn rejects_non_string_yaml_keys() {
+        let err = parse(Format::Yaml, "hosts: {[1, 2]: 3}").unwrap_err();
+        assert_eq!(err.msg, "\"hosts\" keys must be strings");
+        assert_eq!(err.path, ["hosts"]);
+    }
+
+    #[test]
+    fn writes_json() {
+        let value = parse(Format::Yaml, "a: [1, 2.5, x]\nb: {c: null, d: true}").unwrap();
+        assert_eq!(parse(Format::Json, &as_pretty_json(&to_json(&value)).to_string()).unwrap(),
+                   value);
+
+        // JSON has no infinity or NaN.
+        let reals = Value::Array(vec![Value::Real(1.0 / 0.0), Value::Real(0.0 / 0.0)]);
+        assert_eq!(to_json(&reals).to_string(), "[null,null]");
+    }
+}
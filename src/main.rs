@@ -1,5 +1,8 @@
 extern crate ansi_term;
 extern crate libc;
+extern crate regex;
+extern crate rustc_serialize;
+extern crate toml;
 extern crate yaml_rust;
 extern crate time;
 
@@ -9,27 +12,136 @@ extern crate clap;
 mod cli;
 mod lib;
 
-// use std::error::Error;
-// use std::io;
-// use std::io::Write;
-// use std::thread;
-// use std::thread::JoinHandle;
+use std::env;
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::path::Path;
 
-// use log::{Log, Output};
-// use clap::{App, AppSettings, Arg, SubCommand};
-// use remote::Remote;
-// use config::{Config, Host};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use config::Config;
+use log::{ColorChoice, LogFiles, Mode, Output, Timestamps, Verbosity, DEFAULT_TAIL_LINES};
+
+const DEFAULT_KEEP_LOGS: usize = 20;
+
+// Log files are only kept for commands that run on the hosts, so that
+// checking or showing the configuration leaves nothing behind.
+fn output(matches: &ArgMatches,
+          config: &Config,
+          cwd: &Path,
+          log_files: bool)
+          -> Result<Output, Box<Error>> {
+    let output = Output::new();
+    output.set_diagnostics(&config.diagnostics);
+
+    let log_dir = match matches.value_of("log-dir") {
+        Some(dir) => cwd.join(dir),
+        None => config.shadow_dir.join("logs"),
+    };
+
+    let keep = match matches.value_of("keep-logs") {
+        Some(keep) => try!(keep.parse().map_err(|_| "invalid value for --keep-logs")),
+        None => DEFAULT_KEEP_LOGS,
+    };
+
+    if log_files && keep > 0 {
+        output.log_to(try!(LogFiles::create(&log_dir, keep)));
+    }
+
+    if matches.is_present("quiet") {
+        output.set_verbosity(Verbosity::Quiet);
+    } else {
+        match matches.occurrences_of("verbose") {
+            0 => {}
+            1 => output.set_verbosity(Verbosity::Verbose),
+            _ => output.set_verbosity(Verbosity::VeryVerbose),
+        }
+    }
+
+    if let Some(color) = matches.value_of("color") {
+        output.set_color(try!(ColorChoice::parse(color).ok_or("invalid value for --color")));
+    }
+
+    if matches.is_present("timestamps") {
+        let timestamps = matches.value_of("timestamps").unwrap_or("wall");
+        output.set_timestamps(try!(Timestamps::parse(timestamps)
+                                       .ok_or("invalid value for --timestamps")));
+    }
+
+    let tail_lines = match matches.value_of("tail-lines") {
+        Some(lines) => try!(lines.parse().map_err(|_| "invalid value for --tail-lines")),
+        None => DEFAULT_TAIL_LINES,
+    };
+    output.set_tail_lines(tail_lines);
+
+    if let Some(mode) = matches.value_of("output") {
+        output.set_mode(try!(Mode::parse(mode).ok_or("invalid value for --output")));
+    }
+
+    Ok(output)
+}
 
 fn run() -> Result<i32, Box<Error>> {
     let app = App::new("bran")
                   .version(crate_version!())
                   .about("A command line remote builder")
-                  .subcommand(SubCommand::with_name("init").about("Initialize bran"))
+                  .arg(Arg::from_usage("--output [mode] 'How to show output from several hosts'")
+                           .possible_values(&["interleaved", "grouped", "dashboard", "jsonl"]))
+                  .arg(Arg::from_usage("-q --quiet 'Only show the summary and the output of \
+                                        failing hosts'")
+                           .conflicts_with("verbose"))
+                  .arg(Arg::from_usage("-v --verbose... 'Show bran\'s own commands (-vv: and \
+                                        full ssh command lines)'"))
+                  .arg(Arg::from_usage("--color [when] 'When to use colors'")
+                           .possible_values(&["auto", "always", "never"]))
+                  .arg(Arg::from_usage("--timestamps [kind] 'Prefix output with wall clock or \
+                                        relative timestamps, and show command durations'")
+                           .min_values(0)
+                           .possible_values(&["wall", "relative"]))
+                  .arg(Arg::from_usage("--log-dir [dir] 'Write per-host log files under this \
+                                        directory'"))
+                  .arg(Arg::from_usage("--keep-logs [count] 'Number of runs to keep log files \
+                                        for (0 disables log files)'"))
+                  .arg(Arg::from_usage("--tail-lines [count] 'Number of output lines of a \
+                                        failed command to show in the summary'"))
+                  .subcommand(SubCommand::with_name("init")
+                                  .about("Initialize bran, creating bran.yml if there is none")
+                                  .arg(Arg::from_usage("--host [host]... 'Host to build on, as \
+                                                        user@address:build_dir'")
+                                           .number_of_values(1))
+                                  .arg(Arg::from_usage("--build [cmd]... 'Build command, instead \
+                                                        of the one for the project type'")
+                                           .number_of_values(1))
+                                  .arg(Arg::from_usage("-y --yes 'Never ask for settings'")))
                   .subcommand(SubCommand::with_name("push").about("Push files to all hosts"))
                   .subcommand(SubCommand::with_name("clean")
                                   .about("Clean the build directory on all hosts"))
+                  .subcommand(SubCommand::with_name("fetch")
+                                  .about("Copy build artifacts back from all hosts"))
+                  .subcommand(SubCommand::with_name("gc")
+                                  .about("Compact the shadow repository locally and on all hosts"))
                   .subcommand(SubCommand::with_name("build")
                                   .about("Push files to all hosts and run the build command"))
+                  .subcommand(SubCommand::with_name("watch")
+                                  .about("Rebuild on all hosts whenever local files change")
+                                  .setting(AppSettings::TrailingVarArg)
+                                  .arg(Arg::from_usage("[task]... 'Command to run instead of \
+                                                        the build commands'")))
+                  .subcommand(SubCommand::with_name("config")
+                                  .about("Check or show the configuration")
+                                  .setting(AppSettings::SubcommandRequiredElseHelp)
+                                  .subcommand(SubCommand::with_name("check")
+                                                  .about("Check the configuration without \
+                                                          contacting any host"))
+                                  .subcommand(SubCommand::with_name("show")
+                                                  .about("Show the configuration with all \
+                                                          defaults filled in")
+                                                  .arg(Arg::from_usage("--format [format] \
+                                                                        'Output format'")
+                                                           .possible_values(&["yaml", "json"])))
+                                  .subcommand(SubCommand::with_name("schema")
+                                                  .about("Print a JSON Schema for the \
+                                                          configuration")))
                   .subcommand(SubCommand::with_name("run")
                                   .about("Run an ad-hoc command on all hosts")
                                   .setting(AppSettings::TrailingVarArg)
@@ -37,17 +149,74 @@ fn run() -> Result<i32, Box<Error>> {
 
     let matches = app.clone().get_matches();
 
-    if matches.subcommand_matches("init").is_some() {
-        init()
+    if matches.subcommand_name().is_none() {
+        try!(app.print_help());
+        println!("");
+        return Ok(2);
+    }
+
+    // The schema is the same for every project, so it doesn't need one.
+    if let Some(cmd) = matches.subcommand_matches("config") {
+        if cmd.subcommand_matches("schema").is_some() {
+            return cli::config_schema();
+        }
+    }
+
+    if let Some(init) = matches.subcommand_matches("init") {
+        if !config::exists() {
+            let hosts: Vec<&str> = init.values_of("host").map_or(Vec::new(), |v| v.collect());
+            let build: Vec<&str> = init.values_of("build").map_or(Vec::new(), |v| v.collect());
+            try!(cli::scaffold(&hosts, &build, !init.is_present("yes")));
+        }
+    }
+
+    // config::read moves to the project root, so remember where paths given
+    // on the command line are relative to.
+    let cwd = try!(env::current_dir());
+    let inventory = matches.subcommand_matches("init").is_none() &&
+                    matches.subcommand_matches("config")
+                           .map_or(true, |cmd| cmd.subcommand_matches("check").is_none());
+    let config = try!(config::read(inventory));
+
+    let on_hosts = matches.subcommand_matches("init").is_none() &&
+                   matches.subcommand_matches("config").is_none();
+    let output = try!(output(&matches, &config, &cwd, on_hosts));
+
+    let result = if matches.subcommand_matches("init").is_some() {
+        cli::init(config, &output)
+    } else if matches.subcommand_matches("push").is_some() {
+        cli::push(config, &output)
+    } else if matches.subcommand_matches("clean").is_some() {
+        cli::clean(config, &output)
     } else if matches.subcommand_matches("build").is_some() {
-        build()
+        cli::build(config, &output)
+    } else if matches.subcommand_matches("fetch").is_some() {
+        cli::fetch(config, &output)
+    } else if matches.subcommand_matches("gc").is_some() {
+        cli::gc(config, &output)
+    } else if let Some(cmd) = matches.subcommand_matches("watch") {
+        let task = cmd.values_of("task").map(|task| task.collect::<Vec<_>>().join(" "));
+        cli::watch(config, task, &output)
+    } else if let Some(cmd) = matches.subcommand_matches("config") {
+        if let Some(show) = cmd.subcommand_matches("show") {
+            cli::config_show(config, show.value_of("format").unwrap_or("yaml"))
+        } else {
+            cli::config_check(config, &output)
+        }
     } else if let Some(cmd) = matches.subcommand_matches("run") {
-        run(cmd.values_of("cmd").unwrap().collect())
+        cli::run(config, cmd.values_of("cmd").unwrap().collect(), &output)
     } else {
         try!(app.print_help());
         println!("");
         Ok(2)
+    };
+
+    match result {
+        Ok(exit_code) => output.finish(exit_code),
+        Err(ref err) => output.fail(&err.to_string()),
     }
+
+    result
 }
 
 fn main() {